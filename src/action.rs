@@ -1,10 +1,30 @@
-use anyhow::Result;
+use crate::schema;
+use anyhow::{anyhow, Result};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json;
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
 pub struct Message {
+    /// Version of this format; see `schema::SCHEMA_VERSION`.
+    #[serde(default = "schema::default_version")]
+    pub schema_version: u32,
+    /// Can be left out (or 0) if `message_id` is given.
+    #[serde(default)]
     pub uid: u32,
+    /// Find the message by its Message-ID header instead of its UID, which
+    /// changes when the message is moved. Used in place of `uid` if both are
+    /// given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// The mailbox the message is in, as the server names it. INBOX if
+    /// missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mailbox: Option<String>,
+    /// If given, the executor refuses to act unless the mailbox still has
+    /// this UIDVALIDITY, since otherwise the UID could be a different message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid_validity: Option<u32>,
     pub actions: Vec<Action>,
     pub stop: Option<bool>,
 }
@@ -12,17 +32,73 @@ pub struct Message {
 // TODO: check actions vector for equality
 impl PartialEq for Message {
     fn eq(&self, other: &Self) -> bool {
-        self.uid == other.uid && self.stop == other.stop
+        self.schema_version == other.schema_version
+            && self.uid == other.uid
+            && self.message_id == other.message_id
+            && self.mailbox == other.mailbox
+            && self.uid_validity == other.uid_validity
+            && self.stop == other.stop
     }
 }
 
 impl Eq for Message {}
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, PartialEq, Eq)]
 pub enum Action {
+    /// Mailbox names use "/" between levels whatever the server uses. A
+    /// special-use role like `\Archive` or `\Junk` means whichever mailbox
+    /// the server has for it.
     Move(String),
+    /// Put a copy in another mailbox, leaving the original where it is.
+    Copy(String),
+    /// Move to the server's `\Trash` mailbox, or expunge, depending on the
+    /// executor's delete mode.
     Delete,
+    /// Delete permanently, whatever the delete mode.
+    Expunge,
+    /// Move to the server's `\Archive` mailbox.
+    Archive,
     Label,
+    /// Set flags on the message. System flags start with a backslash
+    /// (`\Seen`, `\Flagged`, `\Answered`); anything else is a keyword, like
+    /// `Invoice`. Not `\Deleted`: use `Delete` or `Expunge` for that.
+    AddFlags(Vec<String>),
+    /// Clear flags on the message; the same names as `AddFlags`.
+    RemoveFlags(Vec<String>),
+}
+
+/// The system flags a client is allowed to set (RFC 3501 2.3.2). `\Recent` is
+/// left out because only the server can set it.
+const SYSTEM_FLAGS: &[&str] = &["\\Seen", "\\Answered", "\\Flagged", "\\Deleted", "\\Draft"];
+
+/// Turn a list of flags into the parenthesized list STORE takes, e.g.
+/// `(\Seen Invoice)`. Keywords have to be IMAP atoms, so anything with a
+/// space, bracket, quote or wildcard in it is rejected rather than sent.
+pub fn flag_list(flags: &[String]) -> Result<String> {
+    if flags.is_empty() {
+        return Err(anyhow!("No flags given"));
+    }
+    let flags = flags
+        .iter()
+        .map(|flag| {
+            if let Some(system) = SYSTEM_FLAGS
+                .iter()
+                .find(|f| f.eq_ignore_ascii_case(flag))
+            {
+                return Ok(system.to_string());
+            }
+            let is_atom = !flag.is_empty()
+                && flag
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() && !"(){%*\"]\\".contains(c));
+            if is_atom {
+                Ok(flag.clone())
+            } else {
+                Err(anyhow!("Invalid flag: {:?}", flag))
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(format!("({})", flags.join(" ")))
 }
 
 impl Message {
@@ -44,7 +120,11 @@ mod tests {
     #[test]
     fn back_and_forth() -> Result<()> {
         let msg = Message {
+            schema_version: schema::SCHEMA_VERSION,
             uid: 69,
+            message_id: Some("<1@example.com>".to_string()),
+            mailbox: Some("Archive/2023".to_string()),
+            uid_validity: Some(1),
             actions: vec![Action::Delete],
             stop: None,
         };
@@ -53,4 +133,17 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn flags() -> Result<()> {
+        let flags = vec!["\\seen".to_string(), "\\Flagged".to_string(), "Invoice".to_string()];
+        assert_eq!(flag_list(&flags)?, "(\\Seen \\Flagged Invoice)");
+
+        for bad in ["\\Recent", "\\Bogus", "two words", "(paren", ""] {
+            assert!(flag_list(&[bad.to_string()]).is_err(), "{:?} was allowed", bad);
+        }
+        assert!(flag_list(&[]).is_err());
+
+        Ok(())
+    }
 }
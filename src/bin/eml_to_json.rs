@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use mail_client::binary_libs::fetcher_lib::output_email;
+use mail_client::clustering::ClusterStore;
+use mail_client::email::{Email, ParseFailure};
+use mail_client::mbox;
+use mail_client::projection::Projection;
+use mail_client::threading::ThreadStore;
+use mail_client::wire;
+use std::fs;
+use std::io::Read;
+
+/// Convert .eml files or mboxes into the same stream fetcher produces, so
+/// runner and scripts can be tried out on saved mail.
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let projection = Projection::parse(args.fields.as_deref(), args.exclude_fields.as_deref());
+
+    let inputs = if args.files.is_empty() {
+        let mut data = vec![];
+        std::io::stdin()
+            .read_to_end(&mut data)
+            .context("Couldn't read stdin")?;
+        vec![("stdin".to_string(), data)]
+    } else {
+        args.files
+            .iter()
+            .map(|f| Ok((f.clone(), fs::read(f).with_context(|| format!("Couldn't read {}", f))?)))
+            .collect::<Result<_>>()?
+    };
+
+    // Thread and cluster the messages against each other, but don't touch
+    // fetcher's files.
+    let mut threads = ThreadStore::default();
+    let mut clusters = ClusterStore::default();
+
+    for (name, data) in inputs {
+        let messages = if mbox::is_mbox(&data) {
+            mbox::split(&data)
+        } else {
+            vec![data]
+        };
+
+        for (i, raw) in messages
+            .iter()
+            .enumerate()
+        {
+            match Email::from_rfc822(raw) {
+                Ok(mut email) => {
+                    email.thread_id = Some(threads.add(&email));
+                    email.cluster = clusters.add(&email);
+                    output_email(&email, args.format, &projection)?;
+                }
+                Err(e) => {
+                    eprintln!("Couldn't parse message {} of {}: {}", i + 1, name, e);
+                    wire::write_stdout(&ParseFailure::new(None, &e), args.format)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version)]
+pub struct Args {
+    /// .eml or mbox files to convert. Reads stdin if there aren't any.
+    files: Vec<String>,
+
+    /// Output format. "auto" means newline-delimited JSON.
+    #[clap(long, arg_enum, default_value = "auto")]
+    format: wire::Format,
+
+    /// Only output these fields (comma-separated, e.g. "sender,subject,headers.List-Id").
+    #[clap(long)]
+    fields: Option<String>,
+
+    /// Leave these fields out of the output (comma-separated, e.g. "body,headers").
+    #[clap(long)]
+    exclude_fields: Option<String>,
+}
@@ -1,40 +1,101 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
+use imap::extensions::idle::SetReadTimeout;
+use imap::types::Capabilities;
 use mail_client::action;
-use mail_client::config;
-use std::io;
+use mail_client::commands;
+use mail_client::config::{self, DeleteMode};
+use mail_client::mailbox::Mailboxes;
+use mail_client::schema;
+use mail_client::wire;
+use mail_client::Selected;
+use std::io::{self, Read, Write};
+
+/// What stays the same for every message.
+struct Settings {
+    dry_run: bool,
+    delete_mode: DeleteMode,
+    trash_mailbox: String,
+    capabilities: Capabilities,
+}
+
+/// What we know about the session, carried from one message to the next.
+struct State {
+    mailboxes: Mailboxes,
+    selected: Selected,
+    /// What a real run would have selected. A dry run only examines the
+    /// mailboxes messages start in, since the ones they're moved to might not
+    /// exist yet.
+    would_select: String,
+}
 
 fn main() -> Result<()> {
     let args = Args::parse();
     let config = mail_client::config::get_config(&args.config)?;
     let config = args.overwrite_config(config);
+    let delete_mode = args
+        .delete_mode
+        .or_else(|| {
+            config
+                .executor
+                .as_ref()
+                .and_then(|e| e.delete_mode)
+        })
+        .unwrap_or_default();
+    let trash_mailbox = config
+        .executor
+        .as_ref()
+        .and_then(|e| {
+            e.trash_mailbox
+                .clone()
+        })
+        .unwrap_or_else(|| config::DEFAULT_TRASH_MAILBOX.to_string());
 
-    let mut session = mail_client::login(&config)?;
+    // A dry run only ever EXAMINEs, so it can't change anything, even by
+    // accident.
+    let (mut session, selected) = mail_client::login_to(&config, "INBOX", args.dry_run)?;
+    let settings = Settings {
+        dry_run: args.dry_run,
+        delete_mode,
+        trash_mailbox,
+        capabilities: session.capabilities()?,
+    };
+    let mut reader = wire::Reader::new(io::stdin().lock(), args.format);
+    let mut state = State {
+        mailboxes: if args.dry_run {
+            Mailboxes::new_dry_run(args.create_mailboxes)
+        } else {
+            Mailboxes::new(args.create_mailboxes)
+        },
+        would_select: selected
+            .name
+            .clone(),
+        selected,
+    };
+    let mut skipped = 0;
 
     loop {
-        // Read line from stdin.
-        let mut line = String::new();
-        io::stdin().read_line(&mut line)?;
-
-        // Convert the line to a Message, crashing if it can't be parsed.
-        let message = action::Message::from_json(&line)?;
-
-        // Labels: rust-imap doesn't support the non-standard IMAP extension Gmail
-        // has to support labels (and a few other neat Gmail-specific features).
-        // I've opened a ticket on the rust-imap Github page to see what they think
-        // of adding it. Note that Gmail exposes the labels as read-only with
-        // standard IMAP extension by representing labels as mailboxes, and you can
-        // query those mailboxes to see what messages have that label. However,
-        // there's no way to assign a label to a message, or directly see what
-        // labels a message has, without using their extensions.
-        for a in &message.actions {
-            match a {
-                action::Action::Move(mailbox_name) => {
-                    mail_client::move_email(message.uid, mailbox_name, &mut session)?
-                }
-                action::Action::Delete => mail_client::delete(message.uid, &mut session)?,
-                action::Action::Label => todo!(),
+        // Read the next Message from stdin, crashing if it can't be parsed.
+        let message: action::Message = match reader.read()? {
+            Some(message) => message,
+            None => break,
+        };
+
+        // A problem with one message, like a stale UID, is reported and the
+        // message skipped, so it doesn't hold up the ones after it. Only
+        // trouble with the connection itself stops us.
+        if let Err(e) = act_on(&message, &settings, &mut state, &mut session) {
+            if is_fatal(&e) {
+                return Err(e);
             }
+            eprintln!("Skipping message {}: {:#}", describe(&message), e);
+            skipped += 1;
+            // It may have gone wrong halfway through changing mailboxes, so
+            // don't trust what we think is selected.
+            state
+                .selected
+                .name
+                .clear();
         }
 
         if !args
@@ -45,6 +106,226 @@ fn main() -> Result<()> {
         }
     }
 
+    if skipped > 0 {
+        return Err(anyhow!("Skipped {} message(s); see above for why", skipped));
+    }
+    Ok(())
+}
+
+/// Apply the message's actions, in order.
+fn act_on(
+    message: &action::Message,
+    settings: &Settings,
+    state: &mut State,
+    session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+) -> Result<()> {
+    schema::check_version(message.schema_version)?;
+
+    let mailbox = message
+        .mailbox
+        .as_deref()
+        .unwrap_or("INBOX");
+    if settings.dry_run {
+        print_select(mailbox, &mut state.would_select)?;
+    }
+    if mailbox != state.selected.name {
+        state.selected = if settings.dry_run {
+            mail_client::examine(mailbox, session)?
+        } else {
+            mail_client::select(mailbox, session)?
+        };
+    }
+    let uid = match &message.message_id {
+        Some(message_id) => mail_client::find_by_message_id(message_id, &state.selected, session)?,
+        None if message.uid == 0 => {
+            return Err(anyhow!("The message has neither a UID nor a Message-ID"));
+        }
+        None => {
+            if let Some(expected) = message.uid_validity {
+                if state
+                    .selected
+                    .uid_validity
+                    != Some(expected)
+                {
+                    return Err(anyhow!(
+                        "{} has UIDVALIDITY {:?}, not {}, so UID {} may be a different \
+                         message now; not acting on it",
+                        mailbox,
+                        state
+                            .selected
+                            .uid_validity,
+                        expected,
+                        message.uid
+                    ));
+                }
+            }
+            // A real run would quietly do nothing, but a dry run is
+            // for finding mistakes.
+            if settings.dry_run && !mail_client::uid_exists(message.uid, session)? {
+                return Err(anyhow!(
+                    "There's no message with UID {} in {}",
+                    message.uid,
+                    mailbox
+                ));
+            }
+            message.uid
+        }
+    };
+    // Where the message is now; moving it changes both. If the server
+    // doesn't tell us the new UID, `known_uid` is None and we look it up
+    // by Message-ID. If there's no Message-ID either, or the message has
+    // been deleted, `lost` says why the rest of the actions can't be
+    // applied.
+    let mut mailbox = mailbox.to_string();
+    let mut known_uid = Some(uid);
+    let mut lost: Option<String> = None;
+
+    // Labels: rust-imap doesn't support the non-standard IMAP extension Gmail
+    // has to support labels (and a few other neat Gmail-specific features).
+    // I've opened a ticket on the rust-imap Github page to see what they think
+    // of adding it. Note that Gmail exposes the labels as read-only with
+    // standard IMAP extension by representing labels as mailboxes, and you can
+    // query those mailboxes to see what messages have that label. However,
+    // there's no way to assign a label to a message, or directly see what
+    // labels a message has, without using their extensions.
+    for a in &message.actions {
+        if let Some(reason) = &lost {
+            return Err(anyhow!("Can't apply {:?}: the message {}", a, reason));
+        }
+        if settings.dry_run {
+            print_select(&mailbox, &mut state.would_select)?;
+        } else if mailbox != state.selected.name {
+            state.selected = mail_client::select(&mailbox, session)?;
+        }
+        let uid = match known_uid {
+            Some(uid) => uid,
+            // Nothing really moved, so there's no new UID to find.
+            None if settings.dry_run => 0,
+            None => {
+                let message_id = message
+                    .message_id
+                    .as_deref()
+                    .context("Nothing left to find the message by")?;
+                let found = mail_client::find_by_message_id(message_id, &state.selected, session)?;
+                known_uid = Some(found);
+                found
+            }
+        };
+        let uid_text = match uid {
+            0 => "<new UID>".to_string(),
+            uid => uid.to_string(),
+        };
+
+        // Set by the actions that take the message somewhere else.
+        let mut moved_to = None;
+        let capabilities = &settings.capabilities;
+        let mailboxes = &mut state.mailboxes;
+        let commands = match a {
+            action::Action::Move(mailbox_name) => {
+                let destination = mailboxes.resolve(mailbox_name, session)?;
+                let commands = commands::move_message(&uid_text, &destination, capabilities)?;
+                moved_to = Some(destination);
+                commands
+            }
+            action::Action::Copy(mailbox_name) => {
+                let mailbox = mailboxes.resolve(mailbox_name, session)?;
+                commands::copy_message(&uid_text, &mailbox)?
+            }
+            action::Action::Delete if settings.delete_mode == DeleteMode::Trash => {
+                let destination =
+                    mailboxes.resolve_or_create("\\Trash", &settings.trash_mailbox, session)?;
+                let commands = commands::move_message(&uid_text, &destination, capabilities)?;
+                moved_to = Some(destination);
+                commands
+            }
+            action::Action::Delete | action::Action::Expunge => {
+                lost = Some("has been deleted".to_string());
+                commands::delete(&uid_text, capabilities)?
+            }
+            action::Action::Archive => {
+                let destination = mailboxes.resolve("\\Archive", session)?;
+                let commands = commands::move_message(&uid_text, &destination, capabilities)?;
+                moved_to = Some(destination);
+                commands
+            }
+            action::Action::Label => return Err(anyhow!("Label isn't supported")),
+            action::Action::AddFlags(flags) => commands::add_flags(&uid_text, flags)?,
+            action::Action::RemoveFlags(flags) => commands::remove_flags(&uid_text, flags)?,
+        };
+
+        let outcome = if settings.dry_run {
+            for command in mailboxes
+                .take_commands()
+                .iter()
+                .chain(&commands)
+            {
+                println!("{}", command);
+            }
+            commands::Outcome::default()
+        } else {
+            commands::run(uid, &commands, session)?
+        };
+        if outcome.auto_expunged {
+            eprintln!(
+                "The server expunged UID {} as soon as it was flagged \\Deleted, \
+                 so it decided what deleting means; check its auto-expunge setting.",
+                uid
+            );
+        }
+
+        if let Some(destination) = moved_to {
+            if outcome
+                .new_uid
+                .is_none()
+                && message
+                    .message_id
+                    .is_none()
+                && !settings.dry_run
+            {
+                lost = Some(format!(
+                    "was moved to {}, and the server didn't say what its UID is there",
+                    destination
+                ));
+            }
+            mailbox = destination;
+            known_uid = outcome.new_uid;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether an error means we can't go on at all: the connection failed, or
+/// the server said something we couldn't follow. When the server just
+/// refused a command, or we refused to send one, it's only that message.
+fn is_fatal(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| match cause.downcast_ref::<imap::Error>() {
+            Some(imap::Error::No(_) | imap::Error::Bad(_) | imap::Error::Validate(_)) => false,
+            Some(_) => true,
+            None => cause.is::<io::Error>(),
+        })
+}
+
+/// How to refer to the message in errors.
+fn describe(message: &action::Message) -> String {
+    let mailbox = message
+        .mailbox
+        .as_deref()
+        .unwrap_or("INBOX");
+    match &message.message_id {
+        Some(message_id) => format!("{} in {}", message_id, mailbox),
+        None => format!("with UID {} in {}", message.uid, mailbox),
+    }
+}
+
+/// Print the SELECT a real run would send to get to `mailbox`, if it isn't
+/// there already.
+fn print_select(mailbox: &str, would_select: &mut String) -> Result<()> {
+    if mailbox != would_select {
+        println!("{}", commands::select(mailbox)?);
+        *would_select = mailbox.to_string();
+    }
     Ok(())
 }
 
@@ -74,6 +355,26 @@ pub struct Args {
     /// Don't exit after reading first line of stdin.
     #[clap(long)]
     pub forever: Option<bool>,
+
+    /// Create Move and Copy destinations that don't exist yet, along with any
+    /// parent mailboxes they need.
+    #[clap(long)]
+    pub create_mailboxes: bool,
+
+    /// What the Delete action does: "trash" moves the message to the Trash
+    /// mailbox, "expunge" deletes it for good. Overrides `delete_mode` in the
+    /// config file; the default is "trash".
+    #[clap(long, arg_enum)]
+    pub delete_mode: Option<DeleteMode>,
+
+    /// Check the messages, mailboxes and UIDs, and print the commands that
+    /// would change anything, without sending them.
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Input format. "auto" detects it from the first message.
+    #[clap(long, arg_enum, default_value = "auto")]
+    pub format: wire::Format,
 }
 
 // Note: https://docs.rs/merge/latest/merge/ exists. Can we use that, plus
@@ -10,6 +10,7 @@ pub fn get_config() -> config::Config {
             port: 3993,
         },
         imap_options: None,
+        executor: None,
         scripts: None,
     }
 }
@@ -0,0 +1,36 @@
+use anyhow::Result;
+use clap::{ArgEnum, Parser};
+use mail_client::schema;
+
+/// Print the JSON Schema for the data passed between the pipeline stages.
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let schema = match args.which {
+        Which::Email => serde_json::to_value(schema::email_schema())?,
+        Which::Message => serde_json::to_value(schema::message_schema())?,
+        Which::All => schema::all_schemas(),
+    };
+
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+
+    Ok(())
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+enum Which {
+    /// What fetcher emits and runner reads.
+    Email,
+    /// What runner emits and executor reads.
+    Message,
+    /// Both of them, keyed by name.
+    All,
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version)]
+pub struct Args {
+    /// Which schema to print.
+    #[clap(arg_enum, default_value = "all")]
+    which: Which,
+}
@@ -1,10 +1,13 @@
 use anyhow::anyhow;
+use anyhow::Context;
 use anyhow::Result;
 use clap::Parser;
 use config::EmailField;
 use mail_client::action;
 use mail_client::config;
 use mail_client::email;
+use mail_client::schema;
+use mail_client::wire;
 use std::io;
 use std::process::Command;
 use which::which;
@@ -18,10 +21,38 @@ fn main() -> Result<()> {
     let config = args.overwrite_config(config);
 
     let scripts = config.scripts;
+    let mut reader = wire::Reader::new(io::stdin().lock(), args.format);
 
     loop {
-        let mut line = String::new();
-        io::stdin().read_line(&mut line)?;
+        let raw = match reader.read_raw()? {
+            Some(raw) => raw,
+            None => break,
+        };
+        let format = reader.format();
+
+        // Fetcher sends these along when it couldn't parse a message. There's
+        // nothing for the scripts to look at, so we just report it.
+        if let Ok(failure) = wire::decode::<email::ParseFailure>(&raw, format) {
+            eprintln!(
+                "Skipping unparsable message with UID {:?}: {}",
+                failure.uid, failure.parse_error
+            );
+            continue;
+        }
+
+        // Scripts always get JSON, whatever the pipeline itself is using.
+        let email: email::Email = wire::decode(&raw, format)?;
+        if let Err(e) = schema::check_version(email.schema_version) {
+            eprintln!("Skipping message with UID {}: {}", email.uid, e);
+            continue;
+        }
+        let json = email.to_json()?;
+
+        // Unless told otherwise, answer in the same format we're being fed.
+        let output_format = match args.format {
+            wire::Format::Auto => format,
+            f => f,
+        };
 
         for script in scripts
             .iter()
@@ -32,13 +63,14 @@ fn main() -> Result<()> {
             // to fail.
             let output = call_script(
                 script,
-                &line,
+                &email,
+                &json,
                 script
                     .email_field
                     .as_ref(),
             )?;
             if let Some(msg_str) = output {
-                let stop = output_message(&msg_str)?;
+                let stop = output_message(&msg_str, &email, output_format)?;
                 if stop {
                     break;
                 }
@@ -61,19 +93,30 @@ fn main() -> Result<()> {
 /// exit code.
 fn call_script(
     script: &config::Script,
+    email: &email::Email,
     json: &str,
     email_field: Option<&EmailField>,
 ) -> Result<Option<String>> {
-    let email = email::Email::from_json(json)?;
-
     let cmd_input = match email_field {
         Some(email_field) => match email_field {
             EmailField::ADDRESS => todo!(),
-            EmailField::SUBJECT => email.subject,
-            EmailField::BODY => email.body,
+            EmailField::SUBJECT => email
+                .subject
+                .clone(),
+            EmailField::BODY => email
+                .body
+                .clone(),
             EmailField::UID => email
                 .uid
                 .to_string(),
+            EmailField::NormalizedSubject => email
+                .normalized
+                .subject
+                .clone(),
+            EmailField::NormalizedBody => email
+                .normalized
+                .body
+                .clone(),
         },
 
         None => json.to_string(),
@@ -111,13 +154,28 @@ fn call_script(
     }
 }
 
-/// Convert the JSON string into a message object, output the JSON again on stdout,
-/// and return the `stop` paramater to indicate whether the email should be
-/// processed by future scripts.
-fn output_message(message_str: &str) -> Result<bool> {
-    let message: action::Message = action::Message::from_json(message_str)?;
+/// Convert the JSON string into a message object, output it again on stdout in
+/// the pipeline's format, and return the `stop` paramater to indicate whether
+/// the email should be processed by future scripts. If the script didn't say
+/// which mailbox the message is in, it's filled in from the email.
+fn output_message(message_str: &str, email: &email::Email, format: wire::Format) -> Result<bool> {
+    let mut message: action::Message = action::Message::from_json(message_str)?;
+    schema::check_version(message.schema_version).context("The script's message")?;
+    // The script may have given the Message-ID instead of the UID; that's
+    // still the email we gave it.
+    let same_email = message.uid == email.uid
+        || (message
+            .message_id
+            .is_some()
+            && message.message_id == email.message_id);
+    if same_email && message.mailbox.is_none() {
+        message.mailbox = email
+            .mailbox
+            .clone();
+        message.uid_validity = email.uid_validity;
+    }
 
-    println!("{}", &message);
+    wire::write_stdout(&message, format)?;
 
     Ok(message
         .stop
@@ -150,6 +208,11 @@ pub struct Args {
     /// Don't exit after reading first line of stdin.
     #[clap(long)]
     pub forever: Option<bool>,
+
+    /// Input and output format. "auto" detects the input format and answers
+    /// in the same one.
+    #[clap(long, arg_enum, default_value = "auto")]
+    pub format: wire::Format,
 }
 
 // Note: https://docs.rs/merge/latest/merge/ exists. Can we use that, plus
@@ -1,6 +1,10 @@
 use crate::config;
-use crate::email::Email;
-use crate::login;
+use crate::email::{Email, ParseFailure};
+use crate::{login, login_to, Selected};
+use crate::projection::Projection;
+use crate::clustering::{ClusterStore, CLUSTERS_FILE};
+use crate::threading::{ThreadStore, THREADS_FILE};
+use crate::wire;
 use anyhow::{Context, Result};
 use clap::Parser;
 use imap::types::UnsolicitedResponse;
@@ -19,7 +23,7 @@ pub struct Args {
     #[clap(long)]
     pub catch_up: bool,
 
-    /// Disables writing of the UID. Needed if you're running without write priviliages.
+    /// Disables writing of the UID, thread and cluster files. Needed if you're running without write priviliages.
     #[clap(long)]
     pub no_catch_up_write: bool,
 
@@ -46,6 +50,18 @@ pub struct Args {
     /// password for IMAP authentication.
     #[clap(long)]
     pub password: Option<String>,
+
+    /// Output format. "auto" means newline-delimited JSON.
+    #[clap(long, arg_enum, default_value = "auto")]
+    pub format: wire::Format,
+
+    /// Only output these fields (comma-separated, e.g. "sender,subject,headers.List-Id").
+    #[clap(long)]
+    pub fields: Option<String>,
+
+    /// Leave these fields out of the output (comma-separated, e.g. "body,headers").
+    #[clap(long)]
+    pub exclude_fields: Option<String>,
 }
 
 // Note: https://docs.rs/merge/latest/merge/ exists. Can we use that, plus
@@ -63,13 +79,17 @@ impl Args {
             ..config
         }
     }
+
+    pub fn projection(&self) -> Projection {
+        Projection::parse(self.fields.as_deref(), self.exclude_fields.as_deref())
+    }
 }
 
 pub const CATCH_UP_FILE: &str = "last_message_id";
 
 pub fn catch_up(config: &config::Config, args: &Args) -> Result<()> {
     if let Some(last_uid) = get_last_message_id()? {
-        let mut session = login(config)?;
+        let (mut session, selected) = login_to(config, "INBOX", false)?;
         // The '*' means the newest. We add one to the last seen UID
         // so we don't fetch the one we've already seen. However, *
         // will ALWAYS return at least one result, so we handle that
@@ -77,6 +97,9 @@ pub fn catch_up(config: &config::Config, args: &Args) -> Result<()> {
         let range = format!("{}:*", last_uid + 1);
         let query = "(UID FLAGS INTERNALDATE RFC822 ENVELOPE)";
         let messages = session.uid_fetch(range, query)?;
+        let mut threads = ThreadStore::load(THREADS_FILE)?;
+        let mut clusters = ClusterStore::load(CLUSTERS_FILE)?;
+        let projection = args.projection();
         let mut new_last_uid: Option<u32> = None;
         for msg in messages.iter() {
             new_last_uid = Some(
@@ -88,12 +111,13 @@ pub fn catch_up(config: &config::Config, args: &Args) -> Result<()> {
                 continue;
             }
 
-            let email = Email::from_fetch(msg)?;
-            output_email(&email);
+            output_fetch(msg, &selected, &mut threads, &mut clusters, args.format, &projection)?;
         }
         if let Some(uid) = new_last_uid {
             if !&args.no_catch_up_write {
                 write_last_message_id(uid)?;
+                threads.save(THREADS_FILE)?;
+                clusters.save(CLUSTERS_FILE)?;
             }
         }
         session
@@ -111,11 +135,16 @@ pub fn idle(config: config::Config, args: &Args) -> Result<()> {
     let exit_loop = Arc::new(atomic::AtomicBool::new(false));
     let exit_loop_ctrlc_handler = exit_loop.clone();
     let last_seen_uid = Arc::new(Mutex::new(get_last_message_id()?));
+    let mut threads = ThreadStore::load(THREADS_FILE)?;
+    let mut clusters = ClusterStore::load(CLUSTERS_FILE)?;
+    let projection = args.projection();
 
-    let mut session = login(
+    let (mut session, selected) = login_to(
         &config
             .lock()
             .unwrap(),
+        "INBOX",
+        false,
     )?;
 
     // TODO: hard-kill process if user ctrl-Cs again
@@ -221,11 +250,15 @@ pub fn idle(config: config::Config, args: &Args) -> Result<()> {
             .uid_fetch(uid_set, "(UID FLAGS INTERNALDATE RFC822 ENVELOPE)")
             .expect("Something went wrong with the fetch");
 
+        let mut newest = None;
         for fetch in fetches.iter() {
+            let uid = fetch
+                .uid
+                .context("UID wasn't in the fetch query!")?;
             // The * operator will always return at least one message. In the common
             // case where there are no new messages, that means the one returned is
             // also the one we saw last, in which case we just skip it.
-            if fetch.uid.unwrap()
+            if uid
                 == last_seen_uid
                     .lock()
                     .unwrap()
@@ -234,15 +267,22 @@ pub fn idle(config: config::Config, args: &Args) -> Result<()> {
                 continue;
             }
 
-            let email = Email::from_fetch(&fetch)?;
-            output_email(&email);
+            output_fetch(fetch, &selected, &mut threads, &mut clusters, args.format, &projection)?;
 
-            if !args.no_catch_up_write {
-                write_last_message_id(email.uid)?;
-            }
             *last_seen_uid
                 .lock()
-                .unwrap() = Some(email.uid);
+                .unwrap() = Some(uid);
+            newest = Some(uid);
+        }
+
+        // Saved once per batch: the thread and cluster files can be big, and
+        // a batch is often just one message anyway.
+        if let Some(uid) = newest {
+            if !args.no_catch_up_write {
+                write_last_message_id(uid)?;
+                threads.save(THREADS_FILE)?;
+                clusters.save(CLUSTERS_FILE)?;
+            }
         }
 
         thread::sleep(timeout);
@@ -252,13 +292,37 @@ pub fn idle(config: config::Config, args: &Args) -> Result<()> {
     Ok(())
 }
 
-pub fn output_email(email: &Email) {
-    println!(
-        "{}",
-        email
-            .to_json()
-            .unwrap()
-    )
+pub fn output_email(email: &Email, format: wire::Format, projection: &Projection) -> Result<()> {
+    if projection.is_empty() {
+        wire::write_stdout(email, format)
+    } else {
+        wire::write_stdout(&projection.project(email)?, format)
+    }
+}
+
+/// Parse and output a fetched message. If it can't be parsed, we output a
+/// `ParseFailure` instead and carry on with the rest of the stream.
+pub fn output_fetch(
+    fetch: &imap::types::Fetch,
+    mailbox: &Selected,
+    threads: &mut ThreadStore,
+    clusters: &mut ClusterStore,
+    format: wire::Format,
+    projection: &Projection,
+) -> Result<()> {
+    match Email::from_fetch(fetch) {
+        Ok(mut email) => {
+            email.mailbox = Some(mailbox.name.clone());
+            email.uid_validity = mailbox.uid_validity;
+            email.thread_id = Some(threads.add(&email));
+            email.cluster = clusters.add(&email);
+            output_email(&email, format, projection)
+        }
+        Err(e) => {
+            eprintln!("Couldn't parse message with UID {:?}: {}", fetch.uid, e);
+            wire::write_stdout(&ParseFailure::new(fetch.uid, &e), format)
+        }
+    }
 }
 
 pub fn get_last_message_id() -> Result<Option<u32>> {
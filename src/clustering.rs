@@ -0,0 +1,166 @@
+//! Grouping near-identical messages, so bulk campaigns can be recognised by
+//! how often they turn up.
+//!
+//! Like threading, this keeps its state in a file and adds to it as mail comes
+//! in. Only the last week is remembered: a sighting older than that is
+//! dropped, and so is a cluster with no sightings left. Matching is a linear
+//! scan, which is fine for a week of one person's mail.
+
+use crate::email::fingerprint::{Cluster, Fingerprint};
+use crate::email::Email;
+use crate::store;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+pub const CLUSTERS_FILE: &str = "clusters.json";
+
+/// How long sightings are remembered, in seconds.
+pub const WINDOW: i64 = 7 * 24 * 60 * 60;
+
+/// Minhash similarity at which two messages are counted as the same.
+const MIN_SIMILARITY: f64 = 0.6;
+
+/// Simhash distance at which two messages are counted as the same, for when
+/// the minhash is borderline.
+const MAX_DISTANCE: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ClusterStore {
+    clusters: Vec<StoredCluster>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StoredCluster {
+    id: String,
+    /// The first message's fingerprint. New messages are compared with this
+    /// rather than the latest one, so a cluster can't drift.
+    fingerprint: Fingerprint,
+    /// When each message arrived, oldest first.
+    seen: Vec<i64>,
+}
+
+impl ClusterStore {
+    /// Load the store from `file`, or start an empty one if it doesn't exist
+    /// yet.
+    pub fn load(file: &str) -> Result<ClusterStore> {
+        store::load(file, "clusters")
+    }
+
+    pub fn save(&self, file: &str) -> Result<()> {
+        store::save(self, file, "clusters")
+    }
+
+    /// Record the message as seen now. Returns None if it has no
+    /// fingerprint.
+    pub fn add(&mut self, email: &Email) -> Option<Cluster> {
+        self.add_at(email, store::now())
+    }
+
+    pub fn add_at(&mut self, email: &Email, now: i64) -> Option<Cluster> {
+        let fingerprint = email
+            .fingerprint
+            .as_ref()?;
+        self.expire(now);
+
+        let existing = self
+            .clusters
+            .iter_mut()
+            .find(|c| {
+                c.fingerprint
+                    .similarity(fingerprint)
+                    >= MIN_SIMILARITY
+                    || c.fingerprint
+                        .distance(fingerprint)
+                        <= MAX_DISTANCE
+            });
+        let cluster = match existing {
+            Some(cluster) => cluster,
+            None => {
+                self.clusters
+                    .push(StoredCluster {
+                        id: fingerprint
+                            .simhash
+                            .clone(),
+                        fingerprint: fingerprint.clone(),
+                        seen: vec![],
+                    });
+                self.clusters
+                    .last_mut()
+                    .unwrap()
+            }
+        };
+        cluster
+            .seen
+            .push(now);
+
+        Some(Cluster {
+            id: cluster.id.clone(),
+            count: cluster.seen.len() as u32,
+            first_seen: cluster.seen[0],
+        })
+    }
+
+    fn expire(&mut self, now: i64) {
+        for cluster in &mut self.clusters {
+            cluster
+                .seen
+                .retain(|&t| now - t < WINDOW);
+        }
+        self.clusters
+            .retain(|c| !c.seen.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(body: &str) -> Email {
+        Email {
+            fingerprint: Fingerprint::of(body),
+            ..Default::default()
+        }
+    }
+
+    const OFFER: &str = "Congratulations, you have been selected for an exclusive offer on \
+        premium watches. Reply within 24 hours to claim your discount of 80 percent.";
+
+    #[test]
+    fn counts_within_the_window() {
+        let mut store = ClusterStore::default();
+        let day = 24 * 60 * 60;
+
+        let first = store
+            .add_at(&email(OFFER), 0)
+            .unwrap();
+        let second = store
+            .add_at(&email(&OFFER.replace("80", "75")), day)
+            .unwrap();
+        assert_eq!(first.id, second.id);
+        assert_eq!(second.count, 2);
+        assert_eq!(second.first_seen, 0);
+
+        let unrelated = store
+            .add_at(
+                &email("The build is broken on main again, can someone look at the failing integration tests today?"),
+                day,
+            )
+            .unwrap();
+        assert_ne!(unrelated.id, first.id);
+        assert_eq!(unrelated.count, 1);
+
+        // The first sighting has dropped out of the window by now.
+        let later = store
+            .add_at(&email(OFFER), WINDOW + 1)
+            .unwrap();
+        assert_eq!(later.id, first.id);
+        assert_eq!(later.count, 2);
+        assert_eq!(later.first_seen, day);
+    }
+
+    #[test]
+    fn no_fingerprint() {
+        let mut store = ClusterStore::default();
+        assert_eq!(store.add_at(&email("Thanks!"), 0), None);
+    }
+}
@@ -0,0 +1,264 @@
+//! The commands that change messages, built separately from sending them so
+//! the executor's dry run can print exactly what a real run would send.
+//!
+//! UIDs are passed to the builders as text, since in a dry run a message that
+//! has been moved has a UID nobody knows yet.
+
+use crate::action;
+use crate::quote;
+use crate::uidplus::CopyUid;
+use anyhow::{anyhow, Result};
+use imap::extensions::idle::SetReadTimeout;
+use imap::types::Capabilities;
+use std::io::{Read, Write};
+
+/// What running the commands for one action told us.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// The message's UID in the mailbox it was copied or moved to, if the
+    /// server said (it will if it supports UIDPLUS).
+    pub new_uid: Option<u32>,
+    /// The server expunged the message as soon as it was flagged \Deleted,
+    /// so any commands after that weren't sent.
+    pub auto_expunged: bool,
+}
+
+/// MOVE when the server has it. Otherwise it's COPY, then \Deleted, then UID
+/// EXPUNGE of just this message; without UIDPLUS there's no way to expunge
+/// one message on its own, so it's left marked \Deleted for the next EXPUNGE
+/// rather than taking any other deleted messages with it.
+pub fn move_message(uid: &str, mailbox: &str, capabilities: &Capabilities) -> Result<Vec<String>> {
+    if capabilities.has_str("MOVE") {
+        return Ok(vec![format!("UID MOVE {} {}", uid, quote(mailbox)?)]);
+    }
+
+    let mut commands = copy_message(uid, mailbox)?;
+    commands.push(format!("UID STORE {} +FLAGS.SILENT (\\Deleted)", uid));
+    if capabilities.has_str("UIDPLUS") {
+        commands.push(format!("UID EXPUNGE {}", uid));
+    }
+    Ok(commands)
+}
+
+pub fn copy_message(uid: &str, mailbox: &str) -> Result<Vec<String>> {
+    Ok(vec![format!("UID COPY {} {}", uid, quote(mailbox)?)])
+}
+
+/// Flag the message \Deleted and expunge it. That needs UID EXPUNGE, from
+/// UIDPLUS: a plain EXPUNGE would take every other deleted message in the
+/// mailbox with it.
+pub fn delete(uid: &str, capabilities: &Capabilities) -> Result<Vec<String>> {
+    if !capabilities.has_str("UIDPLUS") {
+        return Err(anyhow!(
+            "The server doesn't support UIDPLUS, which expunging a single message needs"
+        ));
+    }
+    Ok(vec![
+        format!("UID STORE {} +FLAGS.SILENT (\\Deleted)", uid),
+        format!("UID EXPUNGE {}", uid),
+    ])
+}
+
+/// `\Deleted` isn't allowed here: it would get around the delete mode, since
+/// whatever's flagged goes with the next EXPUNGE.
+pub fn add_flags(uid: &str, flags: &[String]) -> Result<Vec<String>> {
+    if flags
+        .iter()
+        .any(|f| f.eq_ignore_ascii_case("\\Deleted"))
+    {
+        return Err(anyhow!(
+            "Use the Delete or Expunge action instead of adding \\Deleted"
+        ));
+    }
+    Ok(vec![format!(
+        "UID STORE {} +FLAGS.SILENT {}",
+        uid,
+        action::flag_list(flags)?
+    )])
+}
+
+pub fn remove_flags(uid: &str, flags: &[String]) -> Result<Vec<String>> {
+    Ok(vec![format!(
+        "UID STORE {} -FLAGS.SILENT {}",
+        uid,
+        action::flag_list(flags)?
+    )])
+}
+
+pub fn create(mailbox: &str) -> Result<String> {
+    Ok(format!("CREATE {}", quote(mailbox)?))
+}
+
+pub fn select(mailbox: &str) -> Result<String> {
+    Ok(format!("SELECT {}", quote(mailbox)?))
+}
+
+/// Send the commands for one action on the message with this UID, in order.
+/// If the server rejects one, the rest aren't sent.
+pub fn run(
+    uid: u32,
+    commands: &[String],
+    session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+) -> Result<Outcome> {
+    let mut outcome = Outcome::default();
+    for command in commands {
+        let deleting = command.starts_with("UID STORE") && command.contains("\\Deleted");
+        // EXPUNGE responses give sequence numbers, so we need this one's to
+        // tell it apart from other messages being expunged at the same time.
+        let seq = if deleting {
+            sequence_number(uid, session)?
+        } else {
+            None
+        };
+        let (response, _) = session.run(command)?;
+        if let Some(new_uid) = CopyUid::from_response(&response).and_then(|c| c.destination_of(uid))
+        {
+            outcome.new_uid = Some(new_uid);
+        }
+        if deleting && expunged(&response, uid, seq) {
+            outcome.auto_expunged = true;
+            break;
+        }
+    }
+    Ok(outcome)
+}
+
+fn sequence_number(
+    uid: u32,
+    session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+) -> Result<Option<u32>> {
+    Ok(session
+        .uid_fetch(uid.to_string(), "UID")?
+        .iter()
+        .find(|f| f.uid == Some(uid))
+        .map(|f| f.message))
+}
+
+/// Whether a raw response says this message was expunged: an EXPUNGE with its
+/// sequence number, or QRESYNC's VANISHED with its UID. Each EXPUNGE moves the
+/// messages after it down one, so an earlier one can change the number.
+fn expunged(response: &[u8], uid: u32, mut seq: Option<u32>) -> bool {
+    for line in String::from_utf8_lossy(response).lines() {
+        let words: Vec<&str> = line
+            .split_ascii_whitespace()
+            .collect();
+        match words[..] {
+            ["*", n, w] if w.eq_ignore_ascii_case("EXPUNGE") => match (n.parse::<u32>(), seq) {
+                (Ok(n), Some(s)) if n == s => return true,
+                (Ok(n), Some(s)) if n < s => seq = Some(s - 1),
+                _ => {}
+            },
+            ["*", w, .., set]
+                if w.eq_ignore_ascii_case("VANISHED") && uid_set_contains(set, uid) =>
+            {
+                return true
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Whether a UID set like "3,7:9" includes `uid`.
+fn uid_set_contains(set: &str, uid: u32) -> bool {
+    set.split(',')
+        .any(|range| match range.split_once(':') {
+            Some((a, b)) => match (a.parse::<u32>(), b.parse::<u32>()) {
+                (Ok(a), Ok(b)) => (a.min(b)..=a.max(b)).contains(&uid),
+                _ => false,
+            },
+            None => range.parse() == Ok(uid),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(extensions: &[&str]) -> Capabilities {
+        let mut line = "* CAPABILITY IMAP4rev1".to_string();
+        for extension in extensions {
+            line = line + " " + extension;
+        }
+        line.push_str("\r\n");
+        Capabilities::parse(line.into_bytes(), &mut std::sync::mpsc::channel().0).unwrap()
+    }
+
+    #[test]
+    fn builds_commands() -> Result<()> {
+        assert_eq!(
+            copy_message("4", "Rechnungen.Gr&APYA3w-e")?,
+            ["UID COPY 4 \"Rechnungen.Gr&APYA3w-e\""]
+        );
+        assert_eq!(
+            delete("4", &capabilities(&["UIDPLUS"]))?,
+            ["UID STORE 4 +FLAGS.SILENT (\\Deleted)", "UID EXPUNGE 4"]
+        );
+        assert!(delete("4", &capabilities(&[])).is_err());
+        assert_eq!(
+            move_message("4", "Trash", &capabilities(&["MOVE"]))?,
+            ["UID MOVE 4 \"Trash\""]
+        );
+        assert_eq!(
+            move_message("4", "Trash", &capabilities(&[]))?,
+            ["UID COPY 4 \"Trash\"", "UID STORE 4 +FLAGS.SILENT (\\Deleted)"]
+        );
+        assert_eq!(
+            add_flags("4", &["\\seen".to_string()])?,
+            ["UID STORE 4 +FLAGS.SILENT (\\Seen)"]
+        );
+        assert!(add_flags("4", &["\\deleted".to_string()]).is_err());
+        // Undeleting is fine, though.
+        assert_eq!(
+            remove_flags("4", &["\\Deleted".to_string()])?,
+            ["UID STORE 4 -FLAGS.SILENT (\\Deleted)"]
+        );
+        assert!(remove_flags("4", &[]).is_err());
+        assert!(create("a\r\nb").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn spots_expunges() {
+        assert!(expunged(
+            b"* 3 EXPUNGE\r\nA5 OK Store completed\r\n",
+            405,
+            Some(3)
+        ));
+        assert!(expunged(b"* VANISHED 405\r\nA5 OK Done\r\n", 405, Some(3)));
+        assert!(expunged(
+            b"* VANISHED 400:410,500\r\nA5 OK Done\r\n",
+            405,
+            Some(3)
+        ));
+        assert!(!expunged(
+            b"* 3 FETCH (FLAGS (\\Deleted))\r\nA5 OK Store completed\r\n",
+            405,
+            Some(3)
+        ));
+    }
+
+    #[test]
+    fn other_messages_expunged() {
+        // Someone else's expunges, before and after ours.
+        assert!(!expunged(
+            b"* 5 EXPUNGE\r\nA5 OK Store completed\r\n",
+            405,
+            Some(3)
+        ));
+        assert!(!expunged(
+            b"* VANISHED 1:4,406\r\nA5 OK Done\r\n",
+            405,
+            Some(3)
+        ));
+        // Message 1 going makes ours 2, and then it's the second EXPUNGE.
+        assert!(!expunged(b"* 1 EXPUNGE\r\nA5 OK Done\r\n", 405, Some(3)));
+        assert!(expunged(
+            b"* 1 EXPUNGE\r\n* 2 EXPUNGE\r\nA5 OK Done\r\n",
+            405,
+            Some(3)
+        ));
+        // If we didn't know where it was, only its UID can tell us.
+        assert!(!expunged(b"* 3 EXPUNGE\r\nA5 OK Done\r\n", 405, None));
+    }
+}
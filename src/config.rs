@@ -1,4 +1,5 @@
 use anyhow::Result;
+use clap::ArgEnum;
 use serde::Deserialize;
 use std::fs::{self};
 
@@ -8,6 +9,7 @@ const DEFAULT_CONFIG_FILE: &str = "autonomous_mail_client.toml";
 pub struct Config {
     pub connection: Connection,
     pub imap_options: Option<ImapOptions>,
+    pub executor: Option<Executor>,
     pub scripts: Option<Vec<Script>>,
 }
 
@@ -19,6 +21,29 @@ pub struct Connection {
     pub password: String,
 }
 
+#[derive(Deserialize, Debug, Default)]
+pub struct Executor {
+    pub delete_mode: Option<DeleteMode>,
+    /// Where `Delete` puts messages if the server doesn't mark a mailbox as
+    /// `\Trash`. Created if it doesn't exist; `DEFAULT_TRASH_MAILBOX` if not
+    /// given.
+    pub trash_mailbox: Option<String>,
+}
+
+pub const DEFAULT_TRASH_MAILBOX: &str = "Trash";
+
+/// What the `Delete` action does. `Expunge` actions always expunge.
+#[derive(ArgEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeleteMode {
+    /// Move the message to the server's `\Trash` mailbox, or the configured
+    /// trash mailbox if it hasn't got one.
+    #[default]
+    Trash,
+    /// Flag the message `\Deleted` and expunge it, which can't be undone.
+    Expunge,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Script {
     pub interpreter: Option<String>,
@@ -33,6 +58,12 @@ pub enum EmailField {
     SUBJECT,
     BODY,
     UID,
+    /// The subject and body with obfuscation undone; see
+    /// `email::normalize`.
+    #[serde(rename = "NORMALIZED_SUBJECT")]
+    NormalizedSubject,
+    #[serde(rename = "NORMALIZED_BODY")]
+    NormalizedBody,
 }
 
 #[derive(Deserialize, Debug)]
@@ -1,120 +1,242 @@
-use anyhow::{anyhow, Context, Result};
-use mailparse;
+use crate::projection::Projection;
+use crate::schema;
+use crate::threading;
+use mailparse::{self, MailHeaderMap};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json;
+use std::collections::BTreeMap;
+use thiserror::Error;
 
-#[derive(Serialize, Deserialize)]
+pub mod auto_reply;
+pub mod bounce;
+pub mod calendar;
+pub mod fingerprint;
+pub mod links;
+pub mod mailing_list;
+pub mod normalize;
+pub mod phishing;
+pub mod quoting;
+pub mod trace;
+
+/// Everything that can go wrong turning a fetch into an `Email`.
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("no UID in fetch")]
+    MissingUid,
+    #[error("no RFC822 body in fetch")]
+    MissingBody,
+    #[error("couldn't parse message: {0}")]
+    Mime(#[from] mailparse::MailParseError),
+}
+
+/// What fetcher emits in place of an `Email` when a message can't be parsed,
+/// so one bad message doesn't take down the whole stream.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ParseFailure {
+    pub uid: Option<u32>,
+    pub parse_error: String,
+}
+
+impl ParseFailure {
+    pub fn new(uid: Option<u32>, error: &ParseError) -> ParseFailure {
+        ParseFailure {
+            uid,
+            parse_error: error.to_string(),
+        }
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<ParseFailure> {
+        serde_json::from_str(json.trim())
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&self)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
 pub struct Email {
+    /// Version of this format; see `schema::SCHEMA_VERSION`.
+    #[serde(default = "schema::default_version")]
+    pub schema_version: u32,
     /// Sender is a Vec because rfc6854 allows multiple senders, we use an
     /// option because even no senders at all is allowed.
     pub sender: Vec<Option<String>>,
     pub subject: String,
     pub body: String,
+    /// The body without quoted replies or the signature: just what the
+    /// sender wrote this time.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub new_content: String,
+    /// The subject and body with obfuscation undone, for rules to match
+    /// against; see `normalize`.
+    #[serde(default, skip_serializing_if = "normalize::Normalized::is_empty")]
+    pub normalized: normalize::Normalized,
     pub uid: u32,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct Address {
-    /// Human readable name, e.g. "Jane Smith"
-    pub name: Option<String>,
-    /// Route, I think?
-    pub adl: Option<String>,
-    /// The part before the @ sign in an address
+    /// The mailbox the message is in, and its UIDVALIDITY. Only filled in by
+    /// fetcher; copy them into the `Message` so the executor acts on the
+    /// right message (runner does this for you).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub mailbox: Option<String>,
-    /// The domain of the address
-    pub host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid_validity: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// References and In-Reply-To combined, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub references: Vec<String>,
+    /// Identifies the conversation this message is part of. Only filled in by
+    /// fetcher, since it needs the thread store; see `threading`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+    /// SPF/DKIM/DMARC verdicts, one entry per Authentication-Results header.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authentication_results: Vec<trace::AuthenticationResults>,
+    /// The Received chain, newest hop first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub received: Vec<trace::ReceivedHop>,
+    /// ARC sets, ordered by instance number.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arc: Vec<trace::ArcSet>,
+    /// Every header, decoded. A header that appears more than once has all its
+    /// values, in order.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, Vec<String>>,
+    /// Mailing list details, if the message came from one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub list: Option<mailing_list::MailingList>,
+    /// Events from any meeting invitations (text/calendar parts).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub calendar: Vec<calendar::CalendarEvent>,
+    /// Every link in the text and HTML bodies, with redirectors unwrapped.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<links::Link>,
+    /// Set if this is a delivery status notification, i.e. a bounce.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bounce: Option<bounce::Bounce>,
+    /// Set if this looks like an automatic reply, e.g. out of office.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_reply: Option<auto_reply::AutoReply>,
+    /// Signs that the message is a phishing attempt, and a score out of 100.
+    #[serde(default, skip_serializing_if = "phishing::Phishing::is_empty")]
+    pub phishing: phishing::Phishing,
+    /// For recognising the same message sent many times over, with small
+    /// changes. None if the body is too short.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<fingerprint::Fingerprint>,
+    /// The group of near-identical messages this one belongs to. Filled in by
+    /// fetcher; see `clustering`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<fingerprint::Cluster>,
 }
 
-impl Address {
-    fn from_imap_address(address: &imap_proto::Address) -> Result<Address> {
-        // TODO: can I simplify this somehow?
-        Ok(Address {
-            name: address
-                .name
-                .as_ref()
-                .and_then(|a| Some(String::from_utf8(a.to_vec()).unwrap())),
-            adl: address
-                .adl
-                .as_ref()
-                .and_then(|a| Some(String::from_utf8(a.to_vec()).unwrap())),
-            mailbox: address
-                .mailbox
-                .as_ref()
-                .and_then(|a| Some(String::from_utf8(a.to_vec()).unwrap())),
-            host: address
-                .host
-                .as_ref()
-                .and_then(|a| Some(String::from_utf8(a.to_vec()).unwrap())),
-        })
-    }
+impl Email {
+    pub fn from_fetch(msg: &imap::types::Fetch) -> Result<Email, ParseError> {
+        let uid = msg
+            .uid
+            .ok_or(ParseError::MissingUid)?;
+        let body = msg
+            .body()
+            .ok_or(ParseError::MissingBody)?;
 
-    /// Returns the address as a "normal" email, e.g. bob@gmail.com. The sender
-    /// can theoretically be empty; rfc6854 gives the example of an automated
-    /// system that doesn't support replies. In the real world I don't know how
-    /// much use that sees -- in my experience, people just use a
-    /// no-reply@whatever.com style address.
-    fn to_simple(&self) -> Option<String> {
-        if self
-            .mailbox
-            .is_some()
-            && self.host.is_some()
-        {
-            Some(format!(
-                "{}@{}",
-                &self
-                    .mailbox
-                    .as_ref()
-                    .unwrap(),
-                &self
-                    .host
-                    .as_ref()
-                    .unwrap()
-            ))
-        } else {
-            None
-        }
-    }
-}
+        // Everything else comes from the message itself. The envelope's
+        // subject and names are still RFC 2047 encoded, and the headers have
+        // the same addresses.
+        let mut email = Email::from_rfc822(body)?;
+        email.uid = uid;
 
-impl Email {
-    pub fn from_fetch(msg: &imap::types::Fetch) -> Result<Email> {
-        let envelope = msg
-            .envelope()
-            .ok_or(anyhow!("No envlope in fetch"))?;
+        Ok(email)
+    }
 
-        let body = msg.body().unwrap();
-        let parsed = mailparse::parse_mail(body)?;
+    /// Parse a raw message, e.g. from a .eml file. There's no UID outside of
+    /// an IMAP server, so `uid` is left as 0, which no server will ever use.
+    pub fn from_rfc822(raw: &[u8]) -> Result<Email, ParseError> {
+        let parsed = mailparse::parse_mail(raw)?;
+        let headers = &parsed.headers;
+        let authentication_results = trace::parse_authentication_results(headers);
+        let received = trace::parse_received(headers);
+        let arc = trace::parse_arc(headers);
+        let parts = all_parts(&parsed);
+        let calendar = calendar::parse_calendar_parts(&parts);
+        let links = links::extract_links(&parts);
+        let phishing = phishing::Phishing::assess(headers, &links, &parts);
+        let message_id = headers
+            .get_first_value("Message-ID")
+            .and_then(|v| {
+                threading::message_ids(&v)
+                    .into_iter()
+                    .next()
+            });
+        let references = threading::references(
+            headers
+                .get_first_value("References")
+                .as_deref(),
+            headers
+                .get_first_value("In-Reply-To")
+                .as_deref(),
+        );
+        // For multipart messages the text is in the first part; otherwise the
+        // message is its own body.
         let body = parsed
             .subparts
-            .get(0)
-            .context("uh-oh, looks like it wasn't a multi-part message!")?
-            .get_body()
-            .context(format!("No body in message? WTF? UID={:?}", msg.uid))?;
+            .first()
+            .unwrap_or(&parsed)
+            .get_body()?;
+        let new_content = quoting::new_content(&body);
 
-        Ok(Email {
-            // jesus christ :|
-            // This looks gnarly as heck; there's got to be a better way.
-            // Is there a macro that can unwrap multiple nested items?
-            sender: envelope
-                .from
-                .as_ref()
-                .unwrap()
-                .as_slice()
+        // Only a best effort: a From we can't make sense of shouldn't lose the
+        // whole message. A missing From is allowed (rfc6854), so it's just an
+        // empty list.
+        let sender = match headers
+            .get_first_header("From")
+            .and_then(|from| mailparse::addrparse_header(from).ok())
+        {
+            Some(addrs) => addrs
                 .iter()
-                .map(|a| {
-                    Address::from_imap_address(a)
-                        .expect("Couldn't create address struct")
-                        .to_simple()
+                .flat_map(|addr| match addr {
+                    mailparse::MailAddr::Single(info) => vec![info.addr.clone()],
+                    mailparse::MailAddr::Group(group) => group
+                        .addrs
+                        .iter()
+                        .map(|info| info.addr.clone())
+                        .collect(),
                 })
+                .map(|addr| Some(addr).filter(|a| a.contains('@')))
                 .collect(),
-            subject: envelope
-                .subject
-                .as_ref()
-                .and_then(|cow| std::str::from_utf8(cow).ok())
-                .unwrap()
-                .to_string(),
+            None => vec![],
+        };
+        let subject = headers
+            .get_first_value("Subject")
+            .unwrap_or_default();
+        let normalized = normalize::Normalized::new(&subject, &body);
+        let fingerprint = fingerprint::Fingerprint::of(&normalized.body);
+
+        Ok(Email {
+            schema_version: schema::SCHEMA_VERSION,
+            sender,
+            subject,
             body,
-            uid: msg.uid.unwrap(),
+            new_content,
+            normalized,
+            uid: 0,
+            mailbox: None,
+            uid_validity: None,
+            message_id,
+            references,
+            thread_id: None,
+            authentication_results,
+            received,
+            arc,
+            headers: header_map(headers),
+            list: mailing_list::MailingList::from_headers(headers),
+            calendar,
+            links,
+            phishing,
+            bounce: bounce::Bounce::from_parts(&parts),
+            auto_reply: auto_reply::AutoReply::from_headers(headers),
+            fingerprint,
+            cluster: None,
         })
     }
 
@@ -125,11 +247,43 @@ impl Email {
     pub fn to_json(&self) -> serde_json::Result<String> {
         serde_json::to_string(&self)
     }
+
+    /// Like `to_json`, but with only the fields the projection allows.
+    pub fn to_json_projected(&self, projection: &Projection) -> serde_json::Result<String> {
+        serde_json::to_string(&projection.project(self)?)
+    }
+}
+
+/// The message and all of its subparts, depth first.
+pub(crate) fn all_parts<'a>(part: &'a mailparse::ParsedMail<'a>) -> Vec<&'a mailparse::ParsedMail<'a>> {
+    let mut parts = vec![part];
+    for sub in &part.subparts {
+        parts.extend(all_parts(sub));
+    }
+    parts
+}
+
+/// Group headers by name. Names are compared case-insensitively, and we keep
+/// the spelling of the first one we see.
+fn header_map(headers: &[mailparse::MailHeader]) -> BTreeMap<String, Vec<String>> {
+    let mut map: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for header in headers {
+        let key = header.get_key();
+        let existing = map
+            .keys()
+            .find(|k| k.eq_ignore_ascii_case(&key))
+            .cloned();
+        map.entry(existing.unwrap_or(key))
+            .or_default()
+            .push(header.get_value());
+    }
+    map
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use anyhow::Result;
 
     #[test]
     fn from_json() -> Result<()> {
@@ -155,19 +309,96 @@ mod tests {
     #[test]
     fn to_json() -> Result<()> {
         let expected_json = concat!(
-            r#"{"sender":["sender.bob@gmail.com"],"#,
+            r#"{"schema_version":3,"sender":["sender.bob@gmail.com"],"#,
             r#""subject":"My first e-mail","#,
             r#""body":"Hello world from SMTP\r\n\r\n","uid":16}"#
         );
         let email = Email {
+            schema_version: schema::SCHEMA_VERSION,
             sender: vec![Some("sender.bob@gmail.com".to_string())],
             subject: "My first e-mail".to_string(),
             body: "Hello world from SMTP\r\n\r\n".to_string(),
             uid: 16,
+            ..Default::default()
         };
 
         assert_eq!(expected_json, email.to_json()?);
 
         Ok(())
     }
+
+    #[test]
+    fn projected() -> Result<()> {
+        let email = Email {
+            sender: vec![Some("sender.bob@gmail.com".to_string())],
+            subject: "My first e-mail".to_string(),
+            body: "Hello world from SMTP\r\n\r\n".to_string(),
+            uid: 16,
+            ..Default::default()
+        };
+        let projection = Projection::parse(Some("sender,subject"), None);
+        let json = email.to_json_projected(&projection)?;
+
+        assert_eq!(json, r#"{"sender":["sender.bob@gmail.com"],"subject":"My first e-mail"}"#);
+        // What's left is for consumers that asked for just these fields; it
+        // isn't a whole email any more.
+        assert!(Email::from_json(&json).is_err());
+        assert!(Email::from_json("{}").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_failure_json() -> Result<()> {
+        let failure = ParseFailure::new(Some(7), &ParseError::MissingBody);
+        let json = failure.to_json()?;
+
+        assert_eq!(json, r#"{"uid":7,"parse_error":"no RFC822 body in fetch"}"#);
+        assert_eq!(failure, ParseFailure::from_json(&json)?);
+        // It must never be mistaken for an email, nor an email for it: runner
+        // tells them apart by trying this first.
+        assert!(Email::from_json(&json).is_err());
+        let email = Email {
+            uid: 7,
+            ..Default::default()
+        };
+        assert!(ParseFailure::from_json(&email.to_json()?).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn from_rfc822() -> Result<()> {
+        let raw = concat!(
+            "From: \"Bob\" <sender.bob@gmail.com>\r\n",
+            "To: alice@example.com\r\n",
+            "Subject: =?UTF-8?Q?Caf=C3=A9_tonight?=\r\n",
+            "Message-ID: <1@gmail.com>\r\n",
+            "\r\n",
+            "Hello world from SMTP\r\n",
+        );
+        let email = Email::from_rfc822(raw.as_bytes())?;
+
+        assert_eq!(email.sender, vec![Some("sender.bob@gmail.com".to_string())]);
+        assert_eq!(email.subject, "Café tonight");
+        assert_eq!(email.body, "Hello world from SMTP\r\n");
+        assert_eq!(email.message_id.as_deref(), Some("1@gmail.com"));
+        assert_eq!(email.uid, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unparseable_from() -> Result<()> {
+        for from in ["<>", "PayPal Support", "\"Bob <bob@example.com>"] {
+            let raw = format!("From: {}\r\nSubject: Hi\r\n\r\nHello\r\n", from);
+            let email = Email::from_rfc822(raw.as_bytes())?;
+            assert_eq!(email.subject, "Hi");
+            assert!(email
+                .sender
+                .iter()
+                .all(Option::is_none));
+        }
+        Ok(())
+    }
 }
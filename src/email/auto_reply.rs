@@ -0,0 +1,161 @@
+//! Recognising automatic replies: vacation messages, "I've received your
+//! ticket" confirmations and the like.
+//!
+//! RFC 3834 says these should carry Auto-Submitted, but plenty of software
+//! predates it or ignores it, so we also look at the older vendor headers
+//! and, for out-of-office replies, the subject.
+
+use mailparse::{MailHeader, MailHeaderMap};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct AutoReply {
+    /// The Auto-Submitted value, e.g. "auto-replied" or "auto-generated".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_submitted: Option<String>,
+    /// Whether it looks like an out-of-office or vacation reply in
+    /// particular.
+    #[serde(default)]
+    pub out_of_office: bool,
+    /// What gave it away, e.g. "X-Autoreply" or "subject".
+    pub signals: Vec<String>,
+}
+
+/// Subject prefixes used by the common clients' out-of-office replies.
+const OUT_OF_OFFICE_SUBJECTS: &[&str] = &[
+    "out of office",
+    "out of the office",
+    "automatic reply",
+    "autoreply",
+    "auto-reply",
+    "auto reply",
+    "auto:",
+    "away from my desk",
+    "on vacation",
+    "abwesenheitsnotiz",
+    "automatische antwort",
+    "réponse automatique",
+    "absence du bureau",
+    "respuesta automática",
+    "fuera de la oficina",
+    "risposta automatica",
+];
+
+impl AutoReply {
+    /// Returns None if nothing suggests the message was sent automatically.
+    pub fn from_headers(headers: &[MailHeader]) -> Option<AutoReply> {
+        // "no" is the explicit way of saying it was written by a person.
+        let auto_submitted = headers
+            .get_first_value("Auto-Submitted")
+            .map(|v| {
+                v.split(';')
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .to_lowercase()
+            })
+            .filter(|v| !v.is_empty() && v != "no");
+        let mut reply = AutoReply {
+            auto_submitted,
+            ..Default::default()
+        };
+        if reply
+            .auto_submitted
+            .is_some()
+        {
+            reply
+                .signals
+                .push("Auto-Submitted".to_string());
+        }
+
+        for header in ["X-Autoreply", "X-Autorespond", "X-Auto-Reply", "X-Vacation"] {
+            if headers
+                .get_first_header(header)
+                .is_some()
+            {
+                reply
+                    .signals
+                    .push(header.to_string());
+                reply.out_of_office = true;
+            }
+        }
+
+        if headers
+            .get_first_value("Precedence")
+            .is_some_and(|p| {
+                p.trim()
+                    .eq_ignore_ascii_case("auto_reply")
+            })
+        {
+            reply
+                .signals
+                .push("Precedence".to_string());
+        }
+
+        let subject = headers
+            .get_first_value("Subject")
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        if OUT_OF_OFFICE_SUBJECTS
+            .iter()
+            .any(|s| subject.starts_with(s))
+        {
+            reply
+                .signals
+                .push("subject".to_string());
+            reply.out_of_office = true;
+        }
+
+        if reply
+            .signals
+            .is_empty()
+        {
+            None
+        } else {
+            Some(reply)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auto_reply(raw: &str) -> Option<AutoReply> {
+        let (headers, _) = mailparse::parse_headers(raw.as_bytes()).unwrap();
+        AutoReply::from_headers(&headers)
+    }
+
+    #[test]
+    fn rfc3834() {
+        let reply = auto_reply(
+            "Auto-Submitted: auto-replied; owner-email=\"x@y\"\r\nSubject: Re: invoice\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            reply
+                .auto_submitted
+                .as_deref(),
+            Some("auto-replied")
+        );
+        assert!(!reply.out_of_office);
+        assert_eq!(reply.signals, vec!["Auto-Submitted"]);
+    }
+
+    #[test]
+    fn out_of_office_by_subject() {
+        let reply = auto_reply("Subject: Automatic reply: Quarterly numbers\r\n\r\n").unwrap();
+        assert!(reply.out_of_office);
+        assert_eq!(reply.auto_submitted, None);
+    }
+
+    #[test]
+    fn written_by_a_person() {
+        assert_eq!(
+            auto_reply("Auto-Submitted: no\r\nSubject: Out of ideas\r\n\r\n"),
+            None
+        );
+    }
+}
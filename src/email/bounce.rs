@@ -0,0 +1,251 @@
+//! Delivery status notifications (RFC 3464): the machine-readable kind of
+//! bounce.
+//!
+//! A DSN is a multipart/report with report-type=delivery-status. It holds a
+//! human readable explanation, a message/delivery-status part with one block
+//! of fields for the report and one per recipient, and usually the original
+//! message (or just its headers).
+
+use mailparse::{MailHeaderMap, ParsedMail};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::threading;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Bounce {
+    /// The server that gave up on the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reporting_mta: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<BouncedRecipient>,
+    /// The Message-ID of the message that bounced, so it can be matched up
+    /// with what was sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_message_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct BouncedRecipient {
+    /// The address delivery was attempted to, without the "rfc822;" prefix.
+    pub address: String,
+    /// "failed", "delayed", "delivered", "relayed" or "expanded".
+    pub action: String,
+    /// The enhanced status code, e.g. "5.1.1". A 5 at the start means the
+    /// failure is permanent, a 4 that it may yet be delivered.
+    pub status: String,
+    /// What the remote server said, e.g. "550 5.1.1 User unknown".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diagnostic: Option<String>,
+}
+
+impl Bounce {
+    /// Returns None unless one of the parts is a delivery status report.
+    pub fn from_parts(parts: &[&ParsedMail]) -> Option<Bounce> {
+        let report = parts
+            .iter()
+            .find(|p| {
+                p.ctype.mimetype == "multipart/report"
+                    && p.ctype
+                        .params
+                        .get("report-type")
+                        .is_some_and(|t| t.eq_ignore_ascii_case("delivery-status"))
+            })?;
+
+        let mut bounce = Bounce::default();
+        for part in &report.subparts {
+            match part
+                .ctype
+                .mimetype
+                .as_str()
+            {
+                "message/delivery-status" | "message/global-delivery-status" => {
+                    if let Ok(body) = part.get_body_raw() {
+                        parse_status(&body, &mut bounce);
+                    }
+                }
+                "message/rfc822"
+                | "text/rfc822-headers"
+                | "message/global"
+                | "message/global-headers" => {
+                    bounce.original_message_id = part
+                        .get_body_raw()
+                        .ok()
+                        .and_then(|raw| {
+                            mailparse::parse_headers(&raw)
+                                .ok()?
+                                .0
+                                .get_first_value("Message-ID")
+                        })
+                        .and_then(|v| {
+                            threading::message_ids(&v)
+                                .into_iter()
+                                .next()
+                        });
+                }
+                _ => {}
+            }
+        }
+
+        Some(bounce)
+    }
+}
+
+/// The delivery-status body is blocks of header fields separated by blank
+/// lines. The first is about the report, the rest are one per recipient.
+fn parse_status(body: &[u8], bounce: &mut Bounce) {
+    let text = String::from_utf8_lossy(body).replace("\r\n", "\n");
+    let blocks: Vec<String> = text
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .map(|b| format!("{}\n\n", b))
+        .collect();
+    let mut blocks = blocks
+        .iter()
+        .filter_map(|b| {
+            mailparse::parse_headers(b.as_bytes())
+                .ok()
+                .map(|(headers, _)| headers)
+        });
+
+    if let Some(report) = blocks.next() {
+        bounce.reporting_mta = report
+            .get_first_value("Reporting-MTA")
+            .map(|v| strip_type(&v));
+    }
+
+    for fields in blocks {
+        let address = match fields
+            .get_first_value("Final-Recipient")
+            .or_else(|| fields.get_first_value("Original-Recipient"))
+        {
+            Some(address) => strip_type(&address),
+            None => continue,
+        };
+        bounce
+            .recipients
+            .push(BouncedRecipient {
+                address,
+                action: fields
+                    .get_first_value("Action")
+                    .map(|a| {
+                        a.trim()
+                            .to_lowercase()
+                    })
+                    .unwrap_or_default(),
+                status: fields
+                    .get_first_value("Status")
+                    .map(|s| {
+                        // Some servers add a comment after the code.
+                        s.split_whitespace()
+                            .next()
+                            .unwrap_or("")
+                            .to_string()
+                    })
+                    .unwrap_or_default(),
+                diagnostic: fields
+                    .get_first_value("Diagnostic-Code")
+                    .map(|d| strip_type(&d))
+                    .filter(|d| !d.is_empty()),
+            });
+    }
+}
+
+/// Most DSN fields start with a type, like "rfc822; bob@example.com" or
+/// "smtp; 550 User unknown".
+fn strip_type(value: &str) -> String {
+    let value = value.trim();
+    match value.split_once(';') {
+        Some((_, rest)) => rest
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::all_parts;
+
+    #[test]
+    fn dsn() {
+        let raw = concat!(
+            "From: MAILER-DAEMON@mx.example.com\r\n",
+            "Subject: Undelivered Mail Returned to Sender\r\n",
+            "Content-Type: multipart/report; report-type=delivery-status; boundary=\"b\"\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "Your message could not be delivered.\r\n",
+            "--b\r\n",
+            "Content-Type: message/delivery-status\r\n",
+            "\r\n",
+            "Reporting-MTA: dns; mx.example.com\r\n",
+            "Arrival-Date: Mon, 2 May 2022 09:14:00 +0000\r\n",
+            "\r\n",
+            "Final-Recipient: rfc822; nobody@example.org\r\n",
+            "Original-Recipient: rfc822;Nobody@example.org\r\n",
+            "Action: failed\r\n",
+            "Status: 5.1.1\r\n",
+            "Diagnostic-Code: smtp; 550 5.1.1 <nobody@example.org>:\r\n",
+            "    Recipient address rejected: User unknown\r\n",
+            "\r\n",
+            "Final-Recipient: rfc822; slow@example.net\r\n",
+            "Action: delayed\r\n",
+            "Status: 4.4.1 (connection timed out)\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: text/rfc822-headers\r\n",
+            "\r\n",
+            "From: campaigns@example.com\r\n",
+            "Message-ID: <campaign-42@example.com>\r\n",
+            "\r\n",
+            "--b--\r\n",
+        );
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let bounce = Bounce::from_parts(&all_parts(&parsed)).unwrap();
+
+        assert_eq!(
+            bounce
+                .reporting_mta
+                .as_deref(),
+            Some("mx.example.com")
+        );
+        assert_eq!(
+            bounce
+                .original_message_id
+                .as_deref(),
+            Some("campaign-42@example.com")
+        );
+        assert_eq!(
+            bounce.recipients,
+            vec![
+                BouncedRecipient {
+                    address: "nobody@example.org".to_string(),
+                    action: "failed".to_string(),
+                    status: "5.1.1".to_string(),
+                    diagnostic: Some(
+                        "550 5.1.1 <nobody@example.org>: Recipient address rejected: User unknown"
+                            .to_string()
+                    ),
+                },
+                BouncedRecipient {
+                    address: "slow@example.net".to_string(),
+                    action: "delayed".to_string(),
+                    status: "4.4.1".to_string(),
+                    diagnostic: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn not_a_bounce() {
+        let parsed = mailparse::parse_mail(b"Subject: hi\r\n\r\nhello\r\n").unwrap();
+        assert_eq!(Bounce::from_parts(&all_parts(&parsed)), None);
+    }
+}
@@ -0,0 +1,398 @@
+//! Meeting invitations: text/calendar parts (RFC 5545), as sent by Outlook,
+//! Google Calendar and friends. We only pull out what filtering rules are
+//! likely to need, not the whole iCalendar model.
+
+use mailparse::ParsedMail;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct CalendarEvent {
+    /// REQUEST, CANCEL, REPLY, etc. Comes from the calendar, or failing that
+    /// the part's Content-Type.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
+    /// Goes up every time the organizer changes the event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub organizer: Option<Attendee>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attendees: Vec<Attendee>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<EventTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<EventTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Attendee {
+    /// The address, without the "mailto:".
+    pub address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    /// ACCEPTED, DECLINED, NEEDS-ACTION, etc.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct EventTime {
+    /// As written, e.g. "20220301T100000" or "20220301".
+    pub value: String,
+    /// The TZID parameter, or "UTC" for times ending in Z. Missing for
+    /// "floating" times, which are in whatever zone the reader is in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    pub all_day: bool,
+    /// Seconds since the epoch. Only filled in when that can be worked out
+    /// without a timezone database, i.e. for UTC times.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+}
+
+/// Find and parse every text/calendar (or .ics attachment) part.
+pub fn parse_calendar_parts(parts: &[&ParsedMail]) -> Vec<CalendarEvent> {
+    parts
+        .iter()
+        .filter(|p| {
+            p.ctype.mimetype == "text/calendar" || p.ctype.mimetype == "application/ics"
+        })
+        .filter_map(|p| {
+            let body = p.get_body().ok()?;
+            let method = p
+                .ctype
+                .params
+                .get("method")
+                .map(|m| m.to_uppercase());
+            Some(parse_ical(&body, method))
+        })
+        .flatten()
+        .collect()
+}
+
+/// Parse the VEVENTs out of an iCalendar document. `fallback_method` is used
+/// when the calendar doesn't have a METHOD of its own.
+pub fn parse_ical(text: &str, fallback_method: Option<String>) -> Vec<CalendarEvent> {
+    let mut events = vec![];
+    let mut method = fallback_method;
+    // Which components we're inside of, so we can ignore the properties of
+    // VALARMs and VTIMEZONEs.
+    let mut stack: Vec<String> = vec![];
+    let mut current: Option<CalendarEvent> = None;
+
+    for line in unfold(text) {
+        let (name, params, value) = match split_property(&line) {
+            Some(p) => p,
+            None => continue,
+        };
+
+        match name.as_str() {
+            "BEGIN" => {
+                let component = value.to_uppercase();
+                if component == "VEVENT" {
+                    current = Some(CalendarEvent::default());
+                }
+                stack.push(component);
+                continue;
+            }
+            "END" => {
+                if stack.pop().as_deref() == Some("VEVENT") {
+                    if let Some(mut event) = current.take() {
+                        event.method = method.clone();
+                        events.push(event);
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        match stack
+            .last()
+            .map(|s| s.as_str())
+        {
+            Some("VCALENDAR") if name == "METHOD" => method = Some(value.to_uppercase()),
+            Some("VEVENT") => {
+                if let Some(event) = current.as_mut() {
+                    apply_property(event, &name, &params, &value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    events
+}
+
+fn apply_property(event: &mut CalendarEvent, name: &str, params: &[(String, String)], value: &str) {
+    match name {
+        "UID" => event.uid = Some(unescape(value)),
+        "SEQUENCE" => {
+            event.sequence = value
+                .trim()
+                .parse()
+                .ok()
+        }
+        "SUMMARY" => event.summary = Some(unescape(value)),
+        "STATUS" => event.status = Some(value.to_uppercase()),
+        "LOCATION" => event.location = Some(unescape(value)),
+        "ORGANIZER" => event.organizer = Some(attendee(params, value)),
+        "ATTENDEE" => event
+            .attendees
+            .push(attendee(params, value)),
+        "DTSTART" => event.start = Some(event_time(params, value)),
+        "DTEND" => event.end = Some(event_time(params, value)),
+        _ => {}
+    }
+}
+
+fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a String> {
+    params
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v)
+}
+
+fn attendee(params: &[(String, String)], value: &str) -> Attendee {
+    let address = value.trim();
+    let address = match address.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => &address[7..],
+        _ => address,
+    };
+    Attendee {
+        address: address.to_string(),
+        name: param(params, "CN").cloned(),
+        role: param(params, "ROLE").cloned(),
+        status: param(params, "PARTSTAT").cloned(),
+    }
+}
+
+fn event_time(params: &[(String, String)], value: &str) -> EventTime {
+    let value = value.trim();
+    let all_day = param(params, "VALUE").map(|v| v.eq_ignore_ascii_case("DATE")) == Some(true)
+        || !value.contains('T');
+    let is_utc = value.ends_with('Z') || value.ends_with('z');
+
+    EventTime {
+        value: value.to_string(),
+        timezone: if is_utc {
+            Some("UTC".to_string())
+        } else {
+            param(params, "TZID").cloned()
+        },
+        all_day,
+        timestamp: if is_utc { utc_timestamp(value) } else { None },
+    }
+}
+
+/// Lines starting with whitespace are continuations of the previous one.
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in text.lines() {
+        match line.chars().next() {
+            Some(' ') | Some('\t') if !lines.is_empty() => lines
+                .last_mut()
+                .unwrap()
+                .push_str(&line[1..]),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// A content line: name, parameters and value.
+type Property = (String, Vec<(String, String)>, String);
+
+/// Split "NAME;PARAM=a;PARAM2="b:c":value" into its pieces. Names are
+/// uppercased; quotes are removed from parameter values.
+fn split_property(line: &str) -> Option<Property> {
+    let mut in_quotes = false;
+    let colon = line
+        .char_indices()
+        .find(|&(_, c)| {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            c == ':' && !in_quotes
+        })?
+        .0;
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+
+    let mut pieces = vec![];
+    let mut current = String::new();
+    in_quotes = false;
+    for c in head.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => pieces.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    pieces.push(current);
+
+    let mut pieces = pieces.into_iter();
+    let name = pieces
+        .next()?
+        .trim()
+        .to_uppercase();
+    let params = pieces
+        .filter_map(|p| {
+            p.split_once('=')
+                .map(|(k, v)| (k.trim().to_uppercase(), v.to_string()))
+        })
+        .collect();
+
+    Some((name, params, value.to_string()))
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') | Some('N') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// "20220301T100000Z" (or a bare date) to seconds since the epoch.
+fn utc_timestamp(value: &str) -> Option<i64> {
+    let digits: String = value
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .collect();
+    let num = |range: std::ops::Range<usize>| -> Option<i64> { digits.get(range)?.parse().ok() };
+
+    let (year, month, day) = (num(0..4)?, num(4..6)?, num(6..8)?);
+    let (hour, minute, second) = if digits.len() >= 14 {
+        (num(8..10)?, num(10..12)?, num(12..14)?)
+    } else {
+        (0, 0, 0)
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date. From Howard
+/// Hinnant's "chrono-Compatible Low-Level Date Algorithms".
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVITE: &str = concat!(
+        "BEGIN:VCALENDAR\r\n",
+        "PRODID:-//Google Inc//Google Calendar 70.9054//EN\r\n",
+        "VERSION:2.0\r\n",
+        "METHOD:REQUEST\r\n",
+        "BEGIN:VTIMEZONE\r\n",
+        "TZID:Europe/Berlin\r\n",
+        "BEGIN:STANDARD\r\n",
+        "DTSTART:19701025T030000\r\n",
+        "TZOFFSETTO:+0100\r\n",
+        "END:STANDARD\r\n",
+        "END:VTIMEZONE\r\n",
+        "BEGIN:VEVENT\r\n",
+        "DTSTART;TZID=Europe/Berlin:20220301T100000\r\n",
+        "DTEND:20220301T100000Z\r\n",
+        "ORGANIZER;CN=Alice Example:mailto:alice@example.com\r\n",
+        "ATTENDEE;CUTYPE=INDIVIDUAL;ROLE=REQ-PARTICIPANT;PARTSTAT=NEEDS-ACTION;\r\n",
+        " CN=\"Smith, Bob\";X-NUM-GUESTS=0:mailto:bob@example.com\r\n",
+        "ATTENDEE;PARTSTAT=ACCEPTED:MAILTO:carol@example.com\r\n",
+        "UID:abc123@google.com\r\n",
+        "SEQUENCE:2\r\n",
+        "LOCATION:Room 4\\, second floor\r\n",
+        "SUMMARY:Planning\r\n",
+        "BEGIN:VALARM\r\n",
+        "SUMMARY:This is an alarm\r\n",
+        "END:VALARM\r\n",
+        "END:VEVENT\r\n",
+        "END:VCALENDAR\r\n",
+    );
+
+    #[test]
+    fn invite() {
+        let events = parse_ical(INVITE, None);
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+
+        assert_eq!(event.method.as_deref(), Some("REQUEST"));
+        assert_eq!(event.uid.as_deref(), Some("abc123@google.com"));
+        assert_eq!(event.sequence, Some(2));
+        assert_eq!(event.summary.as_deref(), Some("Planning"));
+        assert_eq!(event.location.as_deref(), Some("Room 4, second floor"));
+
+        let organizer = event
+            .organizer
+            .as_ref()
+            .unwrap();
+        assert_eq!(organizer.address, "alice@example.com");
+        assert_eq!(organizer.name.as_deref(), Some("Alice Example"));
+
+        assert_eq!(event.attendees.len(), 2);
+        assert_eq!(event.attendees[0].name.as_deref(), Some("Smith, Bob"));
+        assert_eq!(event.attendees[0].role.as_deref(), Some("REQ-PARTICIPANT"));
+        assert_eq!(event.attendees[1].address, "carol@example.com");
+        assert_eq!(event.attendees[1].status.as_deref(), Some("ACCEPTED"));
+
+        let start = event
+            .start
+            .as_ref()
+            .unwrap();
+        assert_eq!(start.timezone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(start.timestamp, None);
+        assert!(!start.all_day);
+
+        let end = event
+            .end
+            .as_ref()
+            .unwrap();
+        assert_eq!(end.timezone.as_deref(), Some("UTC"));
+        assert_eq!(end.timestamp, Some(1646128800));
+    }
+
+    #[test]
+    fn cancellation_method_from_content_type() {
+        let ical = "BEGIN:VCALENDAR\nBEGIN:VEVENT\nUID:x\nSTATUS:cancelled\n\
+                    DTSTART;VALUE=DATE:20220301\nEND:VEVENT\nEND:VCALENDAR\n";
+        let events = parse_ical(ical, Some("CANCEL".to_string()));
+        assert_eq!(events[0].method.as_deref(), Some("CANCEL"));
+        assert_eq!(events[0].status.as_deref(), Some("CANCELLED"));
+        let start = events[0]
+            .start
+            .as_ref()
+            .unwrap();
+        assert!(start.all_day);
+        assert_eq!(start.timezone, None);
+    }
+}
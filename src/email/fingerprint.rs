@@ -0,0 +1,173 @@
+//! Fingerprints for spotting the same message sent over and over with small
+//! changes: a different greeting, a tracking number, a shuffled sentence.
+//!
+//! Both kinds are computed over overlapping three-word runs ("shingles") of
+//! the normalized body. The simhash is a single 64-bit value where similar
+//! texts differ in only a few bits; the minhash is a short list of values
+//! where the fraction that match estimates how many shingles the texts share.
+//! The hashes are FNV-1a rather than std's, so they stay the same between
+//! builds and can be stored.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// How many values are in a minhash.
+pub const MINHASH_SIZE: usize = 32;
+
+/// Texts shorter than this many words don't say enough to compare.
+const MIN_WORDS: usize = 8;
+
+const SHINGLE_WORDS: usize = 3;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// 64 bits as 16 hex digits, since JSON numbers can't hold them all.
+    pub simhash: String,
+    pub minhash: Vec<u32>,
+}
+
+/// Which group of near-identical messages this one belongs to, and how often
+/// the group has been seen; see `clustering`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Cluster {
+    /// Stays the same for every message in the group.
+    pub id: String,
+    /// How many messages in the group have been seen in the last week,
+    /// including this one.
+    pub count: u32,
+    /// When the earliest of those arrived, in seconds since the Unix epoch.
+    pub first_seen: i64,
+}
+
+impl Fingerprint {
+    /// Returns None if the text is too short to fingerprint usefully.
+    pub fn of(text: &str) -> Option<Fingerprint> {
+        let words: Vec<String> = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        if words.len() < MIN_WORDS {
+            return None;
+        }
+
+        let shingles: Vec<u64> = words
+            .windows(SHINGLE_WORDS)
+            .map(|w| {
+                fnv1a(
+                    w.join(" ")
+                        .as_bytes(),
+                )
+            })
+            .collect();
+
+        Some(Fingerprint {
+            simhash: format!("{:016x}", simhash(&shingles)),
+            minhash: minhash(&shingles),
+        })
+    }
+
+    pub fn simhash_bits(&self) -> u64 {
+        u64::from_str_radix(&self.simhash, 16).unwrap_or(0)
+    }
+
+    /// Roughly the fraction of shingles the two texts have in common.
+    pub fn similarity(&self, other: &Fingerprint) -> f64 {
+        let matching = self
+            .minhash
+            .iter()
+            .zip(&other.minhash)
+            .filter(|(a, b)| a == b)
+            .count();
+        matching as f64 / MINHASH_SIZE as f64
+    }
+
+    /// How many bits of the simhashes differ.
+    pub fn distance(&self, other: &Fingerprint) -> u32 {
+        (self.simhash_bits() ^ other.simhash_bits()).count_ones()
+    }
+}
+
+fn simhash(shingles: &[u64]) -> u64 {
+    let mut counts = [0i64; 64];
+    for hash in shingles {
+        for (bit, count) in counts
+            .iter_mut()
+            .enumerate()
+        {
+            if hash >> bit & 1 == 1 {
+                *count += 1;
+            } else {
+                *count -= 1;
+            }
+        }
+    }
+    counts
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count > 0)
+        .fold(0, |acc, (bit, _)| acc | 1 << bit)
+}
+
+/// One minimum per seed, each over the shingle hashes remixed with that seed.
+fn minhash(shingles: &[u64]) -> Vec<u32> {
+    (0..MINHASH_SIZE as u64)
+        .map(|seed| {
+            shingles
+                .iter()
+                .map(|&h| splitmix64(h ^ splitmix64(seed)) as u32)
+                .min()
+                .unwrap_or(u32::MAX)
+        })
+        .collect()
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0xcbf29ce484222325, |hash, &b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAMPAIGN: &str = "Dear customer, your parcel could not be delivered because the \
+        address was incomplete. Please confirm your details within 48 hours at the link below \
+        or the parcel will be returned to the sender. Tracking number 1Z999AA10123456784.";
+
+    #[test]
+    fn near_duplicates_are_similar() {
+        let a = Fingerprint::of(CAMPAIGN).unwrap();
+        let b = Fingerprint::of(
+            &CAMPAIGN
+                .replace("Dear customer", "Hello Jane")
+                .replace("1Z999AA10123456784", "1Z999AA10987654321"),
+        )
+        .unwrap();
+        let other = Fingerprint::of(
+            "Hi all, the quarterly planning meeting has moved to Thursday afternoon in the big \
+             conference room. Bring your draft roadmaps and any open questions for the team.",
+        )
+        .unwrap();
+
+        assert!(a.similarity(&b) > 0.6, "similarity {}", a.similarity(&b));
+        assert!(a.distance(&b) < a.distance(&other));
+        assert!(a.similarity(&other) < 0.2);
+        assert_eq!(a, Fingerprint::of(CAMPAIGN).unwrap());
+    }
+
+    #[test]
+    fn too_short() {
+        assert_eq!(Fingerprint::of("Thanks, see you then"), None);
+    }
+}
@@ -0,0 +1,504 @@
+//! Every URL in the message, and where it really goes.
+//!
+//! Links often pass through a redirector before reaching their destination:
+//! Outlook's SafeLinks, Google's /url, Proofpoint, social media link shims.
+//! The destination is in the wrapper URL itself, so we can unwrap them
+//! without touching the network. Trackers that only keep an opaque ID (most
+//! newsletter click tracking) can't be unwrapped that way and are left alone.
+
+use mailparse::{DispositionType, ParsedMail};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkKind {
+    /// An `<a href>` in an HTML part.
+    Anchor,
+    /// An `<img src>` in an HTML part. Tiny remote images are usually
+    /// tracking pixels.
+    Image,
+    /// A bare URL in a plain text part.
+    Text,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub kind: LinkKind,
+    /// The URL exactly as it appears in the message.
+    pub url: String,
+    /// Where it ends up after unwrapping any redirectors. The same as `url`
+    /// if there weren't any.
+    pub target: String,
+    /// The host of `target`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// The text of the link, for anchors.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// The host the reader thinks they're going to, if the anchor text looks
+    /// like a URL or domain name. When this doesn't match `host`, be
+    /// suspicious.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visible_host: Option<String>,
+    /// The hosts of the redirectors that were unwrapped, outermost first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wrappers: Vec<String>,
+}
+
+impl Link {
+    fn new(kind: LinkKind, url: &str, text: Option<String>) -> Link {
+        let (target, wrappers) = unwrap_redirects(url);
+        let host = host_of(&target);
+        let visible_host = text
+            .as_deref()
+            .and_then(visible_host);
+        Link {
+            kind,
+            url: url.to_string(),
+            target,
+            host,
+            text,
+            visible_host,
+            wrappers,
+        }
+    }
+}
+
+/// Collect the links from every text/plain and text/html part that isn't an
+/// attachment. Duplicates (the same link in both the text and HTML versions,
+/// say) are only listed once.
+pub fn extract_links(parts: &[&ParsedMail]) -> Vec<Link> {
+    let mut links: Vec<Link> = vec![];
+    for part in parts {
+        if part
+            .get_content_disposition()
+            .disposition
+            == DispositionType::Attachment
+        {
+            continue;
+        }
+        let body = match part.get_body() {
+            Ok(body) => body,
+            Err(_) => continue,
+        };
+        let found = match part
+            .ctype
+            .mimetype
+            .as_str()
+        {
+            "text/html" => links_in_html(&body),
+            "text/plain" => links_in_text(&body),
+            _ => continue,
+        };
+        for link in found {
+            if !links
+                .iter()
+                .any(|l| l.url == link.url && l.kind == link.kind && l.text == link.text)
+            {
+                links.push(link);
+            }
+        }
+    }
+    links
+}
+
+pub fn links_in_text(text: &str) -> Vec<Link> {
+    text.split(|c: char| c.is_whitespace() || "<>\"'".contains(c))
+        .filter_map(|word| {
+            let start = word
+                .find("https://")
+                .or_else(|| word.find("http://"))?;
+            // Sentence punctuation isn't part of the URL.
+            let url = word[start..].trim_end_matches(|c: char| ".,;:!?)]}".contains(c));
+            Some(Link::new(LinkKind::Text, url, None))
+        })
+        .collect()
+}
+
+pub fn links_in_html(html: &str) -> Vec<Link> {
+    let mut links = vec![];
+    let lower = html.to_ascii_lowercase();
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let tag_start = pos + offset;
+        let tag_end = match lower[tag_start..].find('>') {
+            Some(end) => tag_start + end,
+            None => break,
+        };
+        let tag = &html[tag_start + 1..tag_end];
+        let tag_name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        pos = tag_end + 1;
+
+        match tag_name.as_str() {
+            "a" => {
+                let href = match attribute(tag, "href") {
+                    Some(href) => href,
+                    None => continue,
+                };
+                // The text is everything up to the closing tag, minus markup.
+                let text_end = lower[pos..]
+                    .find("</a")
+                    .map(|i| pos + i)
+                    .unwrap_or(html.len());
+                let text = collapse_whitespace(&decode_entities(&strip_tags(&html[pos..text_end])));
+                if is_web_url(&href) {
+                    links.push(Link::new(
+                        LinkKind::Anchor,
+                        &href,
+                        Some(text).filter(|t| !t.is_empty()),
+                    ));
+                }
+            }
+            "img" => {
+                if let Some(src) = attribute(tag, "src").filter(|s| is_web_url(s)) {
+                    links.push(Link::new(LinkKind::Image, &src, None));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    links
+}
+
+fn is_web_url(url: &str) -> bool {
+    let lower = url.to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+/// Get an attribute's value out of the inside of a tag, e.g. `a href="x"`.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut search_from = 0;
+    while let Some(offset) = lower[search_from..].find(name) {
+        let start = search_from + offset;
+        search_from = start + name.len();
+        // Make sure we matched the whole attribute name, not the end of
+        // another one (e.g. "data-href").
+        let preceded_ok = lower[..start]
+            .chars()
+            .last()
+            .is_some_and(|c| c.is_whitespace());
+        let rest = lower[search_from..].trim_start();
+        if !preceded_ok || !rest.starts_with('=') {
+            continue;
+        }
+        let value_start = tag.len() - rest.len() + 1;
+        let value = tag[value_start..].trim_start();
+        let value = match value.chars().next() {
+            Some(q @ ('"' | '\'')) => value[1..]
+                .split(q)
+                .next()
+                .unwrap_or(""),
+            _ => value
+                .split(|c: char| c.is_whitespace())
+                .next()
+                .unwrap_or(""),
+        };
+        return Some(decode_entities(value.trim()));
+    }
+    None
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                out.push(' ');
+            }
+            _ if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+fn collapse_whitespace(s: &str) -> String {
+    s.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Decode the handful of entities that show up in URLs and link text.
+pub(crate) fn decode_entities(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        // Entities are short, so only look a few characters ahead (not
+        // bytes: the text after a stray "&" can be anything).
+        let end = match rest
+            .char_indices()
+            .take(12)
+            .find(|&(_, c)| c == ';')
+        {
+            Some((end, _)) => end,
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| {
+                    entity
+                        .strip_prefix('#')
+                        .and_then(|dec| dec.parse().ok())
+                })
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+pub(crate) fn host_of(url: &str) -> Option<String> {
+    Url::parse(url)
+        .ok()?
+        .host_str()
+        .map(|h| {
+            h.trim_end_matches('.')
+                .to_lowercase()
+        })
+}
+
+/// If the anchor text is itself a URL or a bare domain ("paypal.com",
+/// "www.paypal.com/login"), the host it shows.
+fn visible_host(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.contains(char::is_whitespace) {
+        return None;
+    }
+    if is_web_url(text) {
+        return host_of(text);
+    }
+    let candidate = text
+        .split('/')
+        .next()?;
+    let looks_like_domain = candidate.contains('.')
+        && !candidate.starts_with('.')
+        && !candidate.ends_with('.')
+        && candidate
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '.' || c == '-')
+        && candidate
+            .rsplit('.')
+            .next()
+            .is_some_and(|tld| tld.len() >= 2 && tld.chars().all(char::is_alphabetic));
+    if looks_like_domain {
+        host_of(&format!("http://{}", text))
+    } else {
+        None
+    }
+}
+
+/// Keep unwrapping until we get to something that isn't a known redirector.
+fn unwrap_redirects(url: &str) -> (String, Vec<String>) {
+    let mut current = url.to_string();
+    let mut wrappers = vec![];
+    // Nobody legitimately nests these more than a couple of times.
+    for _ in 0..5 {
+        match unwrap_once(&current) {
+            Some(inner) if inner != current => {
+                if let Some(host) = host_of(&current) {
+                    wrappers.push(host);
+                }
+                current = inner;
+            }
+            _ => break,
+        }
+    }
+    (current, wrappers)
+}
+
+fn unwrap_once(url: &str) -> Option<String> {
+    let parsed = Url::parse(url).ok()?;
+    let host = parsed
+        .host_str()?
+        .to_lowercase();
+    let path = parsed.path();
+    let param = |name: &str| {
+        parsed
+            .query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+
+    let inner = if host.ends_with(".safelinks.protection.outlook.com") {
+        param("url")
+    } else if (host == "www.google.com" || host == "google.com") && path == "/url" {
+        param("q").or_else(|| param("url"))
+    } else if host == "urldefense.proofpoint.com" && path.starts_with("/v2/url") {
+        // Proofpoint v2 swaps "%" for "-" and "/" for "_".
+        param("u").and_then(|u| {
+            let unmangled = u
+                .replace('-', "%")
+                .replace('_', "/");
+            percent_decode(&unmangled)
+        })
+    } else if host == "urldefense.com" && path.starts_with("/v3/__") {
+        // v3 keeps the URL as-is between "__" markers.
+        let after = url.split_once("/v3/__")?.1;
+        after
+            .split_once("__;")
+            .map(|(inner, _)| inner.to_string())
+    } else if (host == "l.facebook.com" || host == "lm.facebook.com") && path == "/l.php" {
+        param("u")
+    } else if host == "www.youtube.com" && path == "/redirect" {
+        param("q")
+    } else if (host == "www.linkedin.com" && path.starts_with("/redir/redirect"))
+        || host == "slack-redir.net"
+        || host == "out.reddit.com"
+        || (host == "steamcommunity.com" && path.starts_with("/linkfilter"))
+    {
+        param("url")
+    } else if host == "t.umblr.com" {
+        param("z")
+    } else if host == "click.linksynergy.com" {
+        param("murl")
+    } else {
+        None
+    };
+
+    inner.filter(|i| is_web_url(i))
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_anchors_and_images() {
+        let html = r#"<p>Please <A class="btn" HREF="https://evil.example.net/login?a=1&amp;b=2">
+            <b>www.paypal.com</b></a> now.</p>
+            <img width=1 height=1 src='https://track.example.net/open.gif'>
+            <a href="mailto:bob@example.com">mail</a><a data-href="x" href=https://ok.example.org>Our site</a>"#;
+        let links = links_in_html(html);
+
+        assert_eq!(links.len(), 3);
+        assert_eq!(links[0].kind, LinkKind::Anchor);
+        assert_eq!(links[0].url, "https://evil.example.net/login?a=1&b=2");
+        assert_eq!(links[0].text.as_deref(), Some("www.paypal.com"));
+        assert_eq!(links[0].visible_host.as_deref(), Some("www.paypal.com"));
+        assert_eq!(links[0].host.as_deref(), Some("evil.example.net"));
+        assert_eq!(links[1].kind, LinkKind::Image);
+        assert_eq!(links[1].host.as_deref(), Some("track.example.net"));
+        assert_eq!(links[2].url, "https://ok.example.org");
+        assert_eq!(links[2].visible_host, None);
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(
+            decode_entities("a &amp; b &#x41;&#66; &bogus; &"),
+            "a & b AB &bogus; &"
+        );
+        // Byte 12 falls inside a character here.
+        assert_eq!(decode_entities("&研究开发"), "&研究开发");
+        assert_eq!(decode_entities("&研究开发;"), "&研究开发;");
+    }
+
+    #[test]
+    fn plain_text() {
+        let links = links_in_text("See https://example.com/a?b=c. Or (http://example.org/x), or <https://x.io>");
+        let urls: Vec<_> = links
+            .iter()
+            .map(|l| l.url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            vec!["https://example.com/a?b=c", "http://example.org/x", "https://x.io"]
+        );
+    }
+
+    #[test]
+    fn safelinks() {
+        let (target, wrappers) = unwrap_redirects(
+            "https://nam02.safelinks.protection.outlook.com/?url=https%3A%2F%2Fexample.com%2Fpath%3Fx%3D1&data=05%7C01",
+        );
+        assert_eq!(target, "https://example.com/path?x=1");
+        assert_eq!(wrappers, vec!["nam02.safelinks.protection.outlook.com"]);
+    }
+
+    #[test]
+    fn nested_google_in_safelinks() {
+        let google = "https://www.google.com/url?q=https://example.com/&sa=D";
+        let outer = format!(
+            "https://eur01.safelinks.protection.outlook.com/?url={}",
+            url::form_urlencoded::byte_serialize(google.as_bytes()).collect::<String>()
+        );
+        let link = Link::new(LinkKind::Text, &outer, None);
+        assert_eq!(link.target, "https://example.com/");
+        assert_eq!(link.host.as_deref(), Some("example.com"));
+        assert_eq!(link.wrappers.len(), 2);
+    }
+
+    #[test]
+    fn proofpoint() {
+        let (v2, _) = unwrap_redirects(
+            "https://urldefense.proofpoint.com/v2/url?u=https-3A__example.com_a-3Fb-3Dc&d=DwMF&c=x",
+        );
+        assert_eq!(v2, "https://example.com/a?b=c");
+
+        let (v3, _) = unwrap_redirects("https://urldefense.com/v3/__https://example.com/a__;!!abc$");
+        assert_eq!(v3, "https://example.com/a");
+    }
+
+    #[test]
+    fn ordinary_redirect_params_are_left_alone() {
+        let url = "https://accounts.example.com/login?redirect=https://elsewhere.example.net/";
+        let (target, wrappers) = unwrap_redirects(url);
+        assert_eq!(target, url);
+        assert!(wrappers.is_empty());
+    }
+}
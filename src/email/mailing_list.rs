@@ -0,0 +1,181 @@
+//! Mailing list headers: List-Id (RFC 2919), the List-* URL headers (RFC
+//! 2369), one-click unsubscribe (RFC 8058) and the non-standard but
+//! ubiquitous Precedence.
+
+use mailparse::{MailHeader, MailHeaderMap};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct MailingList {
+    /// The part of List-Id in angle brackets, e.g. "dev.lists.example.com".
+    /// This is the thing to filter on; it doesn't change when the list is
+    /// renamed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The human readable part of List-Id, if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// mailto: and http(s): URLs, in the order the list prefers them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unsubscribe: Vec<String>,
+    /// Whether the https: unsubscribe URL can be POSTed to without any
+    /// further interaction.
+    #[serde(default)]
+    pub one_click_unsubscribe: bool,
+    /// Empty if the list doesn't accept posts ("List-Post: NO").
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub archive: Vec<String>,
+    /// "list", "bulk" or "junk", lowercased.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precedence: Option<String>,
+}
+
+impl MailingList {
+    /// Returns None if the message has none of the list headers.
+    pub fn from_headers(headers: &[MailHeader]) -> Option<MailingList> {
+        let list_id = headers.get_first_value("List-Id");
+        let unsubscribe = headers.get_first_value("List-Unsubscribe");
+        let unsubscribe_post = headers.get_first_value("List-Unsubscribe-Post");
+        let post = headers.get_first_value("List-Post");
+        let archive = headers.get_first_value("List-Archive");
+        let precedence = headers.get_first_value("Precedence");
+
+        if list_id.is_none()
+            && unsubscribe.is_none()
+            && post.is_none()
+            && archive.is_none()
+            && precedence.is_none()
+        {
+            return None;
+        }
+
+        let (name, id) = match list_id.as_deref() {
+            Some(v) => parse_list_id(v),
+            None => (None, None),
+        };
+        let unsubscribe = unsubscribe
+            .as_deref()
+            .map(urls)
+            .unwrap_or_default();
+        // RFC 8058 requires exactly this value, and an https URL to post it to.
+        let one_click_unsubscribe = unsubscribe_post
+            .map(|v| {
+                v.trim()
+                    .eq_ignore_ascii_case("List-Unsubscribe=One-Click")
+            })
+            .unwrap_or(false)
+            && unsubscribe
+                .iter()
+                .any(|u| u.starts_with("https:"));
+
+        Some(MailingList {
+            id,
+            name,
+            unsubscribe,
+            one_click_unsubscribe,
+            post: post
+                .as_deref()
+                .map(urls)
+                .unwrap_or_default(),
+            archive: archive
+                .as_deref()
+                .map(urls)
+                .unwrap_or_default(),
+            precedence: precedence.map(|p| {
+                p.trim()
+                    .to_lowercase()
+            }),
+        })
+    }
+}
+
+/// "Dev Team <dev.example.com>" to (Some("Dev Team"), Some("dev.example.com")).
+/// Some lists leave off the brackets entirely, in which case the whole thing
+/// is the ID.
+fn parse_list_id(value: &str) -> (Option<String>, Option<String>) {
+    let value = value.trim();
+    match (value.find('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => {
+            let name = value[..start]
+                .trim()
+                .trim_matches('"')
+                .trim();
+            let id = value[start + 1..end].trim();
+            (
+                Some(name.to_string()).filter(|n| !n.is_empty()),
+                Some(id.to_string()).filter(|i| !i.is_empty()),
+            )
+        }
+        _ => (None, Some(value.to_string()).filter(|v| !v.is_empty())),
+    }
+}
+
+/// The URLs of a List-* header are each wrapped in angle brackets, and may be
+/// followed by comments.
+fn urls(value: &str) -> Vec<String> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|s| s.split_once('>'))
+        .map(|(url, _)| {
+            url.split_whitespace()
+                .collect::<String>()
+        })
+        .filter(|url| !url.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(raw: &str) -> Option<MailingList> {
+        let (headers, _) = mailparse::parse_headers(raw.as_bytes()).unwrap();
+        MailingList::from_headers(&headers)
+    }
+
+    #[test]
+    fn full_set() {
+        let list = list(concat!(
+            "List-Id: \"Rust Users\" <users.rust-lang.org>\r\n",
+            "List-Unsubscribe: <mailto:unsub@rust-lang.org?subject=unsubscribe>,\r\n",
+            " <https://users.rust-lang.org/unsub/abc>\r\n",
+            "List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n",
+            "List-Post: NO (posting not allowed on this list)\r\n",
+            "List-Archive: <https://users.rust-lang.org/archive>\r\n",
+            "Precedence: Bulk\r\n",
+            "\r\n",
+        ))
+        .unwrap();
+
+        assert_eq!(list.id.as_deref(), Some("users.rust-lang.org"));
+        assert_eq!(list.name.as_deref(), Some("Rust Users"));
+        assert_eq!(
+            list.unsubscribe,
+            vec![
+                "mailto:unsub@rust-lang.org?subject=unsubscribe",
+                "https://users.rust-lang.org/unsub/abc"
+            ]
+        );
+        assert!(list.one_click_unsubscribe);
+        assert!(list.post.is_empty());
+        assert_eq!(list.archive, vec!["https://users.rust-lang.org/archive"]);
+        assert_eq!(list.precedence.as_deref(), Some("bulk"));
+    }
+
+    #[test]
+    fn bare_list_id() {
+        let list = list("List-Id: announce.example.com\r\n\r\n").unwrap();
+        assert_eq!(list.id.as_deref(), Some("announce.example.com"));
+        assert_eq!(list.name, None);
+        assert!(!list.one_click_unsubscribe);
+    }
+
+    #[test]
+    fn not_a_list() {
+        assert_eq!(list("Subject: hi\r\n\r\n"), None);
+    }
+}
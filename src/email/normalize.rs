@@ -0,0 +1,210 @@
+//! Text for matching against, with the usual obfuscation tricks undone.
+//!
+//! Spam gets past filters by writing "ＦＲＥＥ" in full-width letters, putting
+//! zero-width spaces inside words, striking through letters with combining
+//! marks, or swapping in Cyrillic and Greek letters that look like Latin ones.
+//! The normalized text reverses all of that, so a rule can match "free" or
+//! "paypal" without having to think about it. It isn't meant for display.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Normalized {
+    #[serde(default)]
+    pub subject: String,
+    #[serde(default)]
+    pub body: String,
+}
+
+impl Normalized {
+    pub fn new(subject: &str, body: &str) -> Normalized {
+        Normalized {
+            subject: normalize(subject),
+            body: normalize(body),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subject.is_empty() && self.body.is_empty()
+    }
+}
+
+/// Combining marks one letter can carry before the rest count as decoration.
+/// Vietnamese and Yoruba put two on a letter (ệ, ọ̀), but nothing real puts
+/// three.
+const MAX_MARKS: usize = 2;
+
+/// NFKC, then drop invisible characters and decorative combining marks,
+/// replace lookalike letters, and collapse every run of whitespace to one
+/// space. Case is left alone.
+pub fn normalize(text: &str) -> String {
+    // The marks are counted between decomposing and composing, so é and ệ
+    // are one letter and one or two marks whether or not they came
+    // precomposed.
+    let mut marks = 0;
+    let cleaned: String = text
+        .nfkd()
+        .filter(|&c| !is_invisible(c))
+        .filter(|&c| {
+            if !('\u{0300}'..='\u{036f}').contains(&c) {
+                marks = 0;
+                return true;
+            }
+            marks += 1;
+            !is_overlay(c) && marks <= MAX_MARKS
+        })
+        .nfc()
+        .collect();
+
+    cleaned
+        .split_whitespace()
+        .map(unconfuse_word)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Marks drawn through the letter rather than above or below it, which no
+/// language uses; they're how text gets struck through.
+fn is_overlay(c: char) -> bool {
+    ('\u{0334}'..='\u{0338}').contains(&c)
+}
+
+fn is_invisible(c: char) -> bool {
+    matches!(c,
+        '\u{00ad}' // soft hyphen
+        | '\u{034f}' // combining grapheme joiner
+        | '\u{061c}' // Arabic letter mark
+        | '\u{115f}' | '\u{1160}' | '\u{3164}' | '\u{ffa0}' // Hangul fillers
+        | '\u{180e}' // Mongolian vowel separator
+        | '\u{200b}'..='\u{200f}' // zero-width space, (non-)joiner, direction marks
+        | '\u{202a}'..='\u{202e}' // bidi embeddings and overrides
+        | '\u{2060}'..='\u{2064}' // word joiner, invisible operators
+        | '\u{2066}'..='\u{206f}' // bidi isolates, deprecated format characters
+        | '\u{fe00}'..='\u{fe0f}' // variation selectors
+        | '\u{feff}' // byte order mark
+        | '\u{e0000}'..='\u{e007f}' // tag characters
+    )
+}
+
+/// Replace lookalike letters, but only in words where it's plausibly a
+/// disguise: the word mixes them with ASCII letters, or is made of nothing
+/// but lookalikes. Real Russian or Greek words nearly always contain letters
+/// with no Latin twin, so they're left as they are.
+fn unconfuse_word(word: &str) -> String {
+    let has_ascii = word
+        .chars()
+        .any(|c| c.is_ascii_alphabetic());
+    let all_confusable = word
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .all(|c| confusable(c).is_some() || c.is_ascii_alphabetic());
+    if !all_confusable && !has_ascii {
+        return word.to_string();
+    }
+    word.chars()
+        .map(|c| confusable(c).unwrap_or(c))
+        .collect()
+}
+
+/// Cyrillic and Greek letters that are indistinguishable from a Latin one in
+/// most fonts.
+fn confusable(c: char) -> Option<char> {
+    Some(match c {
+        // Cyrillic
+        'а' => 'a',
+        'в' => 'b',
+        'е' => 'e',
+        'һ' => 'h',
+        'і' => 'i',
+        'ј' => 'j',
+        'к' => 'k',
+        'ӏ' => 'l',
+        'м' => 'm',
+        'н' => 'h',
+        'о' => 'o',
+        'р' => 'p',
+        'ԛ' => 'q',
+        'ѕ' => 's',
+        'с' => 'c',
+        'т' => 't',
+        'у' => 'y',
+        'ԝ' => 'w',
+        'х' => 'x',
+        'ԁ' => 'd',
+        'А' => 'A',
+        'В' => 'B',
+        'Е' => 'E',
+        'Н' => 'H',
+        'І' => 'I',
+        'Ј' => 'J',
+        'К' => 'K',
+        'М' => 'M',
+        'О' => 'O',
+        'Р' => 'P',
+        'Ѕ' => 'S',
+        'С' => 'C',
+        'Т' => 'T',
+        'Х' => 'X',
+        'У' => 'Y',
+        // Greek
+        'α' => 'a',
+        'ε' => 'e',
+        'ι' => 'i',
+        'κ' => 'k',
+        'ν' => 'v',
+        'ο' => 'o',
+        'ρ' => 'p',
+        'τ' => 't',
+        'υ' => 'u',
+        'χ' => 'x',
+        'Α' => 'A',
+        'Β' => 'B',
+        'Ε' => 'E',
+        'Ζ' => 'Z',
+        'Η' => 'H',
+        'Ι' => 'I',
+        'Κ' => 'K',
+        'Μ' => 'M',
+        'Ν' => 'N',
+        'Ο' => 'O',
+        'Ρ' => 'P',
+        'Τ' => 'T',
+        'Υ' => 'Y',
+        'Χ' => 'X',
+        // Latin letters that aren't ASCII
+        'ı' => 'i',
+        'ɡ' => 'g',
+        'ɑ' => 'a',
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undoes_obfuscation() {
+        // Full-width, zero-width spaces, a strikethrough and Cyrillic letters.
+        assert_eq!(normalize("ＦＲＥＥ  offer"), "FREE offer");
+        assert_eq!(normalize("Pay\u{200b}Pal\u{feff} account"), "PayPal account");
+        assert_eq!(normalize("V\u{0336}iagra"), "Viagra");
+        assert_eq!(normalize("Z\u{0301}\u{0302}\u{0303}\u{0304}algo"), "Ź\u{0302}algo");
+        assert_eq!(normalize("Раураl login"), "Paypal login");
+        assert_eq!(normalize("ℌello\u{00a0}\r\n\tworld"), "Hello world");
+    }
+
+    #[test]
+    fn leaves_real_text_alone() {
+        assert_eq!(normalize("Привет, café"), "Привет, café");
+        assert_eq!(normalize("Καλημέρα"), "Καλημέρα");
+        // Marks with no precomposed letter to go into stay too.
+        assert_eq!(
+            normalize("E\u{0301} ku\u{0301} a\u{0300}a\u{0301}ro\u{0323}\u{0300}"),
+            "É kú àárọ̀"
+        );
+        assert_eq!(normalize("Tiê\u{0301}ng Viê\u{0323}t"), "Tiếng Việt");
+    }
+}
@@ -0,0 +1,440 @@
+//! Cheap, local phishing heuristics.
+//!
+//! None of these prove anything on their own; plenty of legitimate mail has a
+//! Reply-To elsewhere or a tracking link whose text is a different domain.
+//! Each signal adds to a score and comes with a reason, so rules can set their
+//! own threshold and scripts can show why a message was flagged.
+
+use mailparse::{MailAddr, MailHeader, MailHeaderMap, ParsedMail};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::links::Link;
+use super::normalize;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Phishing {
+    /// The sum of the signals' weights, capped at 100.
+    pub score: u32,
+    pub signals: Vec<Signal>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Signal {
+    /// Which check fired, e.g. "reply_to_mismatch".
+    pub kind: String,
+    /// What exactly it found, for a person to read.
+    pub reason: String,
+    pub weight: u32,
+}
+
+const DISPLAY_NAME_MISMATCH: u32 = 30;
+const LOOKALIKE_DOMAIN: u32 = 30;
+const PUNYCODE_DOMAIN: u32 = 15;
+const REPLY_TO_MISMATCH: u32 = 15;
+const LINK_TEXT_MISMATCH: u32 = 30;
+const IP_ADDRESS_LINK: u32 = 15;
+const EXECUTABLE_ATTACHMENT: u32 = 40;
+
+/// Brands that get impersonated the most. A domain that looks like one of
+/// these without being it is a strong signal.
+const BRANDS: &[&str] = &[
+    "amazon",
+    "apple",
+    "bankofamerica",
+    "chase",
+    "dhl",
+    "docusign",
+    "dropbox",
+    "facebook",
+    "fedex",
+    "google",
+    "icloud",
+    "instagram",
+    "linkedin",
+    "microsoft",
+    "netflix",
+    "office365",
+    "outlook",
+    "paypal",
+    "usps",
+    "wellsfargo",
+];
+
+/// Attachment types that run code when opened, or that are mostly used to
+/// smuggle something that does.
+const EXECUTABLE_EXTENSIONS: &[&str] = &[
+    "apk", "app", "bat", "cmd", "com", "cpl", "dll", "docm", "exe", "hta", "img", "iso", "jar",
+    "js", "jse", "lnk", "msi", "pif", "ps1", "reg", "scr", "vbe", "vbs", "wsf", "xlsm",
+];
+
+impl Phishing {
+    pub fn assess(headers: &[MailHeader], links: &[Link], parts: &[&ParsedMail]) -> Phishing {
+        let mut phishing = Phishing::default();
+
+        let from = headers
+            .get_first_header("From")
+            .and_then(|h| first_address(h));
+        let from_domain = from
+            .as_ref()
+            .and_then(|(_, addr)| domain_of(addr));
+
+        if let Some((Some(name), addr)) = &from {
+            phishing.check_display_name(name, addr);
+        }
+
+        let reply_to = headers
+            .get_first_header("Reply-To")
+            .and_then(|h| first_address(h));
+        if let (Some(from_domain), Some((_, reply_addr))) = (&from_domain, &reply_to) {
+            if let Some(reply_domain) = domain_of(reply_addr) {
+                if base_domain(&reply_domain) != base_domain(from_domain) {
+                    phishing.add(
+                        "reply_to_mismatch",
+                        format!("Replies go to {}, not {}", reply_domain, from_domain),
+                        REPLY_TO_MISMATCH,
+                    );
+                }
+            }
+        }
+
+        let mut domains: Vec<String> = from_domain
+            .into_iter()
+            .chain(
+                reply_to
+                    .as_ref()
+                    .and_then(|(_, addr)| domain_of(addr)),
+            )
+            .chain(
+                links
+                    .iter()
+                    .filter_map(|l| l.host.clone()),
+            )
+            .collect();
+        domains.sort();
+        domains.dedup();
+        for domain in &domains {
+            phishing.check_domain(domain);
+        }
+
+        for link in links {
+            phishing.check_link(link);
+        }
+
+        for part in parts {
+            phishing.check_attachment(part);
+        }
+
+        phishing.score = phishing
+            .signals
+            .iter()
+            .map(|s| s.weight)
+            .sum::<u32>()
+            .min(100);
+        phishing
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signals
+            .is_empty()
+    }
+
+    fn add(&mut self, kind: &str, reason: String, weight: u32) {
+        // One of each kind is enough to make the point; twenty mismatched
+        // links aren't twenty times as suspicious.
+        if self
+            .signals
+            .iter()
+            .any(|s| s.kind == kind)
+        {
+            return;
+        }
+        self.signals
+            .push(Signal {
+                kind: kind.to_string(),
+                reason,
+                weight,
+            });
+    }
+
+    /// "PayPal Support <support@evil.example>" is fine as far as it goes, but
+    /// "support@paypal.com <support@evil.example>" isn't.
+    fn check_display_name(&mut self, name: &str, addr: &str) {
+        let addr_domain = match domain_of(addr) {
+            Some(domain) => domain,
+            None => return,
+        };
+        let shown = name
+            .split(|c: char| c.is_whitespace() || "<>()\"',;".contains(c))
+            .filter_map(|word| match word.rsplit_once('@') {
+                Some((_, domain)) => Some(domain.to_lowercase()),
+                None if looks_like_domain(word) => Some(word.to_lowercase()),
+                None => None,
+            })
+            .find(|domain| base_domain(domain) != base_domain(&addr_domain));
+        if let Some(shown) = shown {
+            self.add(
+                "display_name_mismatch",
+                format!(
+                    "Sender's name shows {} but the address is at {}",
+                    shown, addr_domain
+                ),
+                DISPLAY_NAME_MISMATCH,
+            );
+        }
+    }
+
+    fn check_domain(&mut self, domain: &str) {
+        if domain
+            .split('.')
+            .any(|label| label.starts_with("xn--"))
+        {
+            self.add(
+                "punycode_domain",
+                format!("{} is an internationalized domain", domain),
+                PUNYCODE_DOMAIN,
+            );
+        }
+
+        let base = base_domain(domain);
+        let name = base
+            .split('.')
+            .next()
+            .unwrap_or("");
+        let skeleton = skeleton(name);
+        for brand in BRANDS {
+            // Either the whole name, or a piece of it like "paypal-secure".
+            let imitates = name != *brand
+                && skeleton
+                    .split('-')
+                    .any(|part| part == *brand);
+            if imitates {
+                self.add(
+                    "lookalike_domain",
+                    format!("{} looks like {}", domain, brand),
+                    LOOKALIKE_DOMAIN,
+                );
+            }
+        }
+    }
+
+    fn check_link(&mut self, link: &Link) {
+        let host = match &link.host {
+            Some(host) => host,
+            None => return,
+        };
+        if let Some(visible) = &link.visible_host {
+            if base_domain(visible) != base_domain(host) {
+                self.add(
+                    "link_text_mismatch",
+                    format!("A link shows {} but goes to {}", visible, host),
+                    LINK_TEXT_MISMATCH,
+                );
+            }
+        }
+        if host
+            .parse::<std::net::Ipv4Addr>()
+            .is_ok()
+            || host.starts_with('[')
+        {
+            self.add(
+                "ip_address_link",
+                format!("A link goes straight to the IP address {}", host),
+                IP_ADDRESS_LINK,
+            );
+        }
+    }
+
+    fn check_attachment(&mut self, part: &ParsedMail) {
+        let disposition = part.get_content_disposition();
+        let filename = disposition
+            .params
+            .get("filename")
+            .or_else(|| {
+                part.ctype
+                    .params
+                    .get("name")
+            });
+        let filename = match filename {
+            Some(filename) => filename.to_lowercase(),
+            None => return,
+        };
+        let extension = filename
+            .rsplit('.')
+            .next()
+            .unwrap_or("");
+        if filename.contains('.') && EXECUTABLE_EXTENSIONS.contains(&extension) {
+            self.add(
+                "executable_attachment",
+                format!("{} can run code when opened", filename),
+                EXECUTABLE_ATTACHMENT,
+            );
+        }
+    }
+}
+
+/// The display name (if any) and address of the first mailbox in a header.
+fn first_address(header: &MailHeader) -> Option<(Option<String>, String)> {
+    let list = mailparse::addrparse_header(header).ok()?;
+    list.iter()
+        .find_map(|addr| match addr {
+            MailAddr::Single(info) => Some(info.clone()),
+            MailAddr::Group(group) => group
+                .addrs
+                .first()
+                .cloned(),
+        })
+        .map(|info| (info.display_name, info.addr))
+}
+
+fn domain_of(addr: &str) -> Option<String> {
+    addr.rsplit_once('@')
+        .map(|(_, domain)| {
+            domain
+                .trim_end_matches('>')
+                .to_lowercase()
+        })
+        .filter(|d| !d.is_empty())
+}
+
+fn looks_like_domain(word: &str) -> bool {
+    let word = word.trim_end_matches('.');
+    word.contains('.')
+        && !word.starts_with('.')
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        && word
+            .rsplit('.')
+            .next()
+            .is_some_and(|tld| {
+                tld.len() >= 2
+                    && tld
+                        .chars()
+                        .all(|c| c.is_ascii_alphabetic())
+            })
+}
+
+/// The part of a domain someone actually registered, near enough:
+/// "mail.example.com" is "example.com" and "www.example.co.uk" is
+/// "example.co.uk". Without the public suffix list this guesses at the
+/// two-letter country domains, which is right for the common ones.
+fn base_domain(domain: &str) -> String {
+    let labels: Vec<&str> = domain
+        .trim_end_matches('.')
+        .split('.')
+        .collect();
+    let keep = match labels.as_slice() {
+        [.., second, tld]
+            if tld.len() == 2
+                && ["co", "com", "org", "net", "ac", "gov", "edu"].contains(second) =>
+        {
+            3
+        }
+        _ => 2,
+    };
+    labels[labels
+        .len()
+        .saturating_sub(keep)..]
+        .join(".")
+}
+
+/// What a domain label looks like at a glance: lookalike letters and digits
+/// swapped for the letters they imitate.
+fn skeleton(label: &str) -> String {
+    normalize::normalize(label)
+        .to_lowercase()
+        .replace("rn", "m")
+        .replace("vv", "w")
+        .chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | '!' | '|' => 'l',
+            '3' => 'e',
+            '4' | '@' => 'a',
+            '5' | '$' => 's',
+            '7' => 't',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::{all_parts, links};
+
+    fn assess(raw: &str) -> Phishing {
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let parts = all_parts(&parsed);
+        let links = links::extract_links(&parts);
+        Phishing::assess(&parsed.headers, &links, &parts)
+    }
+
+    fn kinds(phishing: &Phishing) -> Vec<&str> {
+        phishing
+            .signals
+            .iter()
+            .map(|s| s.kind.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn classic_phish() {
+        let phishing = assess(concat!(
+            "From: \"service@paypal.com\" <alerts@paypa1-secure.example>\r\n",
+            "Reply-To: help@mailbox.example.net\r\n",
+            "Content-Type: multipart/mixed; boundary=\"b\"\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<a href=\"http://203.0.113.9/login\">https://www.paypal.com/signin</a>\r\n",
+            "--b\r\n",
+            "Content-Type: application/octet-stream; name=\"invoice.pdf.exe\"\r\n",
+            "Content-Disposition: attachment; filename=\"invoice.pdf.exe\"\r\n",
+            "\r\n",
+            "MZ\r\n",
+            "--b--\r\n",
+        ));
+
+        assert_eq!(
+            kinds(&phishing),
+            vec![
+                "display_name_mismatch",
+                "reply_to_mismatch",
+                "lookalike_domain",
+                "link_text_mismatch",
+                "ip_address_link",
+                "executable_attachment",
+            ]
+        );
+        assert_eq!(phishing.score, 100);
+    }
+
+    #[test]
+    fn ordinary_mail() {
+        let phishing = assess(concat!(
+            "From: PayPal <service@paypal.com>\r\n",
+            "Reply-To: help@mail.paypal.com\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<a href=\"https://www.paypal.com/x\">paypal.com</a> <a href=\"https://example.com\">here</a>\r\n",
+        ));
+        assert!(phishing.is_empty());
+        assert_eq!(phishing.score, 0);
+    }
+
+    #[test]
+    fn punycode() {
+        let phishing = assess("From: x@xn--pypal-4ve.com\r\n\r\nhi\r\n");
+        assert_eq!(kinds(&phishing), vec!["punycode_domain"]);
+    }
+
+    #[test]
+    fn base_domains() {
+        assert_eq!(base_domain("mail.example.com"), "example.com");
+        assert_eq!(base_domain("www.example.co.uk"), "example.co.uk");
+        assert_eq!(base_domain("localhost"), "localhost");
+    }
+}
@@ -0,0 +1,198 @@
+//! Separating what the sender wrote from what they were replying to.
+//!
+//! There's no standard for any of this, so it's a collection of the patterns
+//! the common clients use: "On <date>, <someone> wrote:" attributions,
+//! `>`-quoted lines, Outlook's "-----Original Message-----" and header blocks,
+//! the `-- ` signature delimiter, and phone sign-offs. It errs on the side of
+//! keeping text; a missed quote is less harmful than a dropped sentence.
+
+/// The body with quoted replies and the signature removed.
+pub fn new_content(body: &str) -> String {
+    let body = body.replace("\r\n", "\n");
+    let lines: Vec<&str> = body
+        .lines()
+        .collect();
+
+    // Everything from the first reply header down is the previous message.
+    let end = (0..lines.len())
+        .find(|&i| starts_quoted_reply(&lines[i..]))
+        .unwrap_or(lines.len());
+    let mut kept: Vec<&str> = lines[..end]
+        .iter()
+        .copied()
+        .filter(|line| !is_quoted(line))
+        .collect();
+
+    strip_signature(&mut kept);
+
+    while kept
+        .last()
+        .is_some_and(|l| l.trim().is_empty())
+    {
+        kept.pop();
+    }
+    let start = kept
+        .iter()
+        .position(|l| !l.trim().is_empty())
+        .unwrap_or(kept.len());
+
+    kept[start..]
+        .iter()
+        .map(|l| l.trim_end())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn is_quoted(line: &str) -> bool {
+    line.trim_start()
+        .starts_with('>')
+}
+
+/// Does a quoted reply start at the first of these lines?
+fn starts_quoted_reply(lines: &[&str]) -> bool {
+    let line = lines[0].trim();
+    let lower = line.to_lowercase();
+
+    if is_attribution(&lower) {
+        return true;
+    }
+    // Long attributions get wrapped by the client, so "wrote:" can be on
+    // the next line.
+    if let Some(next) = lines.get(1) {
+        let joined = format!("{} {}", lower, next.trim().to_lowercase());
+        if attribution_start(&lower) && is_attribution(&joined) {
+            return true;
+        }
+    }
+
+    if lower.starts_with("-----original message-----")
+        || lower.starts_with("----- original message -----")
+    {
+        return true;
+    }
+
+    // Outlook puts a rule and then a block of headers above the quoted
+    // message. The block alone is enough; some versions skip the rule.
+    let header_block = |from: usize| {
+        let block: Vec<String> = lines
+            .iter()
+            .skip(from)
+            .take(5)
+            .map(|l| {
+                l.trim()
+                    .to_lowercase()
+            })
+            .collect();
+        block
+            .first()
+            .is_some_and(|l| l.starts_with("from:"))
+            && block
+                .iter()
+                .any(|l| l.starts_with("sent:") || l.starts_with("date:"))
+            && block
+                .iter()
+                .any(|l| l.starts_with("subject:") || l.starts_with("to:"))
+    };
+    if line.len() >= 10
+        && line
+            .chars()
+            .all(|c| c == '_' || c == '-')
+    {
+        return header_block(1);
+    }
+    header_block(0)
+}
+
+fn attribution_start(lower: &str) -> bool {
+    ["on ", "le ", "am ", "el ", "il "]
+        .iter()
+        .any(|p| lower.starts_with(p))
+}
+
+/// "On Tue, 3 May 2022 at 10:00, Bob <bob@example.com> wrote:", and its
+/// equivalents from French, German, Spanish and Italian clients.
+fn is_attribution(lower: &str) -> bool {
+    let lower = lower.trim_end();
+    attribution_start(lower)
+        && [
+            "wrote:",
+            "a écrit :",
+            "a écrit:",
+            "schrieb:",
+            "escribió:",
+            "ha scritto:",
+        ]
+        .iter()
+        .any(|s| lower.ends_with(s))
+}
+
+/// Cut off the signature: from a `-- ` delimiter if there is one, otherwise
+/// a trailing "Sent from my ..." line.
+fn strip_signature(lines: &mut Vec<&str>) {
+    // Only the real delimiter: a bare "--" line is as likely to be a divider
+    // or part of a command line as the start of a signature.
+    if let Some(i) = lines
+        .iter()
+        .rposition(|l| *l == "-- ")
+    {
+        lines.truncate(i);
+        return;
+    }
+
+    let last = lines
+        .iter()
+        .rposition(|l| !l.trim().is_empty());
+    if let Some(i) = last {
+        let lower = lines[i]
+            .trim()
+            .to_lowercase();
+        if lower.starts_with("sent from my ")
+            || lower.starts_with("get outlook for ")
+            || lower.starts_with("sent from mail for ")
+            || lower.starts_with("sent from yahoo mail")
+        {
+            lines.truncate(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gmail_reply() {
+        let body = "Sounds good, see you then.\r\n\r\nOn Tue, 3 May 2022 at 10:00, Bob Smith <bob@example.com>\r\nwrote:\r\n> Lunch on Friday?\r\n>\r\n> Bob\r\n";
+        assert_eq!(new_content(body), "Sounds good, see you then.");
+    }
+
+    #[test]
+    fn interleaved_quotes_and_signature() {
+        let body = "> Can you make it?\nYes.\n> And Bob?\nNo, he's away.\n\n-- \nAlice\nExample Corp\n";
+        assert_eq!(new_content(body), "Yes.\nNo, he's away.");
+    }
+
+    #[test]
+    fn outlook_reply() {
+        let body = "Approved.\n\nSent from my iPhone\n\n________________________________\nFrom: Carol <carol@example.com>\nSent: Monday, May 2, 2022 9:14 AM\nTo: Dave\nSubject: Expense report\n\nPlease approve.\n";
+        assert_eq!(new_content(body), "Approved.");
+
+        let original = "Fine by me\n-----Original Message-----\nFrom: Carol\nPlease approve.";
+        assert_eq!(new_content(original), "Fine by me");
+    }
+
+    #[test]
+    fn bare_dashes_are_content() {
+        let body = "Run it like this:\n\n    cargo run --\n--\nthen check the output.\n";
+        assert_eq!(
+            new_content(body),
+            "Run it like this:\n\n    cargo run --\n--\nthen check the output."
+        );
+    }
+
+    #[test]
+    fn nothing_to_strip() {
+        let body = "On reflection, I think we should go.\nFrom: the team\n";
+        assert_eq!(new_content(body), "On reflection, I think we should go.\nFrom: the team");
+    }
+}
@@ -0,0 +1,520 @@
+//! Parsing for the headers that servers stamp on a message as it travels:
+//! Authentication-Results (RFC 8601), Received (RFC 5321) and the ARC set
+//! (RFC 8617). Nothing here verifies anything -- we only report what the
+//! provider already decided.
+
+use mailparse::{MailHeader, MailHeaderMap};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct AuthenticationResults {
+    /// The server that did the checking, e.g. "mx.google.com".
+    pub authserv_id: String,
+    pub results: Vec<AuthResult>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct AuthResult {
+    /// "spf", "dkim", "dmarc", "arc", etc. Always lowercase.
+    pub method: String,
+    /// "pass", "fail", "softfail", "none", etc. Always lowercase.
+    pub result: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Everything of the form ptype.property=value, e.g. "smtp.mailfrom" or
+    /// "header.d".
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
+}
+
+/// One Received header. The list on `Email` is in header order, so the first
+/// hop is the one closest to us and the last is closest to the sender.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReceivedHop {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// The address the sending host connected from, taken from the bracketed
+    /// part of the "from" clause.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub with: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    /// Seconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+}
+
+/// The three ARC headers that share an instance number.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ArcSet {
+    pub instance: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authentication_results: Option<AuthenticationResults>,
+    /// The "cv" tag from ARC-Seal: "none", "pass" or "fail".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_validation: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seal: Option<ArcSignature>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_signature: Option<ArcSignature>,
+}
+
+/// The interesting tags of an ARC-Seal or ARC-Message-Signature. The
+/// signature itself is left out on purpose.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ArcSignature {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selector: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+}
+
+impl AuthenticationResults {
+    /// Parse the value of an Authentication-Results header. Returns None if
+    /// there isn't even an authserv-id.
+    pub fn parse(value: &str) -> Option<AuthenticationResults> {
+        let value = strip_comments(value);
+        let mut sections = split_outside_quotes(&value, ';').into_iter();
+
+        // The authserv-id can be followed by a version number, which we don't
+        // care about.
+        let authserv_id = sections
+            .next()?
+            .split_whitespace()
+            .next()?
+            .to_string();
+
+        let results = sections
+            .filter_map(|s| AuthResult::parse(&s))
+            .collect();
+
+        Some(AuthenticationResults {
+            authserv_id,
+            results,
+        })
+    }
+
+    /// Convenience lookup for the first result of a method, e.g.
+    /// `verdict("dmarc") == Some("pass")`.
+    pub fn verdict(&self, method: &str) -> Option<&str> {
+        self.results
+            .iter()
+            .find(|r| r.method == method)
+            .map(|r| r.result.as_str())
+    }
+}
+
+impl AuthResult {
+    fn parse(resinfo: &str) -> Option<AuthResult> {
+        let mut pairs = key_value_pairs(resinfo).into_iter();
+
+        // "none" on its own means no checks were done.
+        let (method, result) = pairs.next()?;
+        let result = result?;
+        // The method can carry a version, e.g. "dkim/1".
+        let method = method
+            .split('/')
+            .next()?
+            .to_lowercase();
+
+        let mut reason = None;
+        let mut properties = BTreeMap::new();
+        for (key, value) in pairs {
+            match (key.to_lowercase().as_str(), value) {
+                ("reason", Some(v)) => reason = Some(v),
+                (k, Some(v)) if k.contains('.') => {
+                    properties.insert(k.to_string(), v);
+                }
+                _ => {}
+            }
+        }
+
+        Some(AuthResult {
+            method,
+            result: result.to_lowercase(),
+            reason,
+            properties,
+        })
+    }
+}
+
+impl ReceivedHop {
+    /// Parse the value of a Received header. Every clause is optional, so
+    /// this never fails; at worst you get an empty hop.
+    pub fn parse(value: &str) -> ReceivedHop {
+        // The date comes after the last semicolon.
+        let (clauses, date) = match value.rfind(';') {
+            Some(i) => (&value[..i], Some(value[i + 1..].trim())),
+            None => (value, None),
+        };
+
+        let mut hop = ReceivedHop {
+            timestamp: date.and_then(|d| mailparse::dateparse(&strip_comments(d)).ok()),
+            ..Default::default()
+        };
+
+        // The IP lives in a comment, so we have to look for it before throwing
+        // the comments away.
+        hop.from_ip = from_clause(clauses).and_then(bracketed_ip);
+
+        let stripped = strip_comments(clauses);
+        let mut tokens = stripped.split_whitespace();
+        while let Some(token) = tokens.next() {
+            let slot = match token
+                .to_lowercase()
+                .as_str()
+            {
+                "from" => &mut hop.from,
+                "by" => &mut hop.by,
+                "with" => &mut hop.with,
+                "id" => &mut hop.id,
+                "for" => &mut hop.recipient,
+                _ => continue,
+            };
+            if let Some(v) = tokens.next() {
+                *slot = Some(
+                    v.trim_matches(|c| c == '<' || c == '>')
+                        .to_string(),
+                );
+            }
+        }
+
+        hop
+    }
+}
+
+impl ArcSignature {
+    fn parse(value: &str) -> (Option<u32>, Option<String>, ArcSignature) {
+        let tags = tag_list(value);
+        let signature = ArcSignature {
+            domain: tags.get("d").cloned(),
+            selector: tags.get("s").cloned(),
+            algorithm: tags.get("a").cloned(),
+            timestamp: tags
+                .get("t")
+                .and_then(|t| t.parse().ok()),
+        };
+        (
+            tags.get("i")
+                .and_then(|i| i.parse().ok()),
+            tags.get("cv").cloned(),
+            signature,
+        )
+    }
+}
+
+pub fn parse_authentication_results(headers: &[MailHeader]) -> Vec<AuthenticationResults> {
+    headers
+        .get_all_values("Authentication-Results")
+        .iter()
+        .filter_map(|v| AuthenticationResults::parse(v))
+        .collect()
+}
+
+pub fn parse_received(headers: &[MailHeader]) -> Vec<ReceivedHop> {
+    headers
+        .get_all_values("Received")
+        .iter()
+        .map(|v| ReceivedHop::parse(v))
+        .collect()
+}
+
+/// Group the ARC headers by instance. Headers without a usable "i=" tag are
+/// dropped, since there's no way to tell which set they belong to.
+pub fn parse_arc(headers: &[MailHeader]) -> Vec<ArcSet> {
+    let mut sets: BTreeMap<u32, ArcSet> = BTreeMap::new();
+    fn get_set(sets: &mut BTreeMap<u32, ArcSet>, instance: u32) -> &mut ArcSet {
+        sets.entry(instance)
+            .or_insert_with(|| ArcSet {
+                instance,
+                ..Default::default()
+            })
+    }
+
+    for value in headers.get_all_values("ARC-Authentication-Results") {
+        // Same syntax as Authentication-Results, but with an "i=N;" in front.
+        let (instance, rest) = match value.split_once(';') {
+            Some(split) => split,
+            None => continue,
+        };
+        if let Some(instance) = instance
+            .trim()
+            .strip_prefix("i=")
+            .and_then(|i| i.trim().parse().ok())
+        {
+            get_set(&mut sets, instance).authentication_results = AuthenticationResults::parse(rest);
+        }
+    }
+
+    for value in headers.get_all_values("ARC-Seal") {
+        if let (Some(instance), cv, seal) = ArcSignature::parse(&value) {
+            let set = get_set(&mut sets, instance);
+            set.chain_validation = cv;
+            set.seal = Some(seal);
+        }
+    }
+
+    for value in headers.get_all_values("ARC-Message-Signature") {
+        if let (Some(instance), _, signature) = ArcSignature::parse(&value) {
+            get_set(&mut sets, instance).message_signature = Some(signature);
+        }
+    }
+
+    sets.into_values()
+        .collect()
+}
+
+/// Remove (possibly nested) parenthesized comments, leaving quoted strings
+/// alone.
+fn strip_comments(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut depth = 0;
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for c in s.chars() {
+        if escaped {
+            escaped = false;
+            if depth == 0 {
+                out.push(c);
+            }
+            continue;
+        }
+        match c {
+            '\\' => {
+                escaped = true;
+                if depth == 0 {
+                    out.push(c);
+                }
+            }
+            '"' if depth == 0 => {
+                in_quotes = !in_quotes;
+                out.push(c);
+            }
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes && depth > 0 => {
+                depth -= 1;
+                // Keep the tokens on either side of the comment apart.
+                out.push(' ');
+            }
+            _ if depth == 0 => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+fn split_outside_quotes(s: &str, sep: char) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut in_quotes = false;
+    for c in s.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.push(c);
+            }
+            c if c == sep && !in_quotes => parts.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    parts.push(current);
+    parts
+        .into_iter()
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// Split "a=b c = "d e" f" into [(a, Some(b)), (c, Some(d e)), (f, None)].
+fn key_value_pairs(s: &str) -> Vec<(String, Option<String>)> {
+    // Glue "key = value" together so we can split on whitespace.
+    let mut glued = String::with_capacity(s.len());
+    let mut in_quotes = false;
+    for c in s.chars() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        }
+        if !in_quotes && c == '=' {
+            let trimmed = glued.trim_end().len();
+            glued.truncate(trimmed);
+            glued.push('=');
+            continue;
+        }
+        if !in_quotes && c.is_whitespace() && glued.ends_with('=') {
+            continue;
+        }
+        glued.push(if !in_quotes && c.is_whitespace() { ' ' } else { c });
+    }
+
+    split_outside_quotes(&glued, ' ')
+        .into_iter()
+        .map(|token| match token.split_once('=') {
+            Some((k, v)) => (k.to_string(), Some(v.trim_matches('"').to_string())),
+            None => (token, None),
+        })
+        .collect()
+}
+
+/// Parse a DKIM-style "tag=value; tag=value" list. Whitespace inside values
+/// (which happens when long signatures get folded) is removed.
+fn tag_list(s: &str) -> BTreeMap<String, String> {
+    s.split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| {
+            (
+                k.trim().to_lowercase(),
+                v.split_whitespace()
+                    .collect::<String>(),
+            )
+        })
+        .collect()
+}
+
+/// The part of a Received header between "from" and "by", including comments.
+fn from_clause(clauses: &str) -> Option<&str> {
+    let lower = clauses.to_lowercase();
+    let start = lower
+        .find("from ")
+        .filter(|&i| i == 0 || lower[..i].ends_with(char::is_whitespace))?;
+    let end = lower[start..]
+        .find(" by ")
+        .map(|i| start + i)
+        .unwrap_or(clauses.len());
+    Some(&clauses[start..end])
+}
+
+/// Find the first bracketed IP literal, e.g. "[209.85.220.41]" or
+/// "[IPv6:2001:db8::1]".
+fn bracketed_ip(clause: &str) -> Option<String> {
+    clause
+        .split('[')
+        .skip(1)
+        .filter_map(|s| s.split(']').next())
+        .map(|s| {
+            s.trim_start_matches("IPv6:")
+                .trim_start_matches("ipv6:")
+        })
+        .find(|s| s.parse::<IpAddr>().is_ok())
+        .map(|s| s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authentication_results() {
+        let ar = AuthenticationResults::parse(concat!(
+            "mx.google.com;\r\n",
+            "       dkim=pass header.i=@example.com header.s=20210112 header.b=\"abc/123\";\r\n",
+            "       spf=pass (google.com: domain of bob@example.com designates 1.2.3.4 as ",
+            "permitted sender) smtp.mailfrom=bob@example.com;\r\n",
+            "       dmarc=fail (p=REJECT sp=REJECT dis=NONE) reason=\"policy; bad\" ",
+            "header.from=example.com"
+        ))
+        .unwrap();
+
+        assert_eq!(ar.authserv_id, "mx.google.com");
+        assert_eq!(ar.results.len(), 3);
+        assert_eq!(ar.verdict("dkim"), Some("pass"));
+        assert_eq!(ar.verdict("spf"), Some("pass"));
+        assert_eq!(ar.verdict("dmarc"), Some("fail"));
+        assert_eq!(ar.results[0].properties["header.b"], "abc/123");
+        assert_eq!(ar.results[1].properties["smtp.mailfrom"], "bob@example.com");
+        assert_eq!(ar.results[2].reason.as_deref(), Some("policy; bad"));
+    }
+
+    #[test]
+    fn authentication_results_none() {
+        let ar = AuthenticationResults::parse("example.org 1; none").unwrap();
+        assert_eq!(ar.authserv_id, "example.org");
+        assert!(ar.results.is_empty());
+    }
+
+    #[test]
+    fn received() {
+        let hop = ReceivedHop::parse(concat!(
+            "from mail-sor-f41.google.com (mail-sor-f41.google.com. [209.85.220.41])\r\n",
+            "        by mx.google.com with SMTPS id x1sor123.2022.03.01\r\n",
+            "        for <me@example.com>\r\n",
+            "        (Google Transport Security);\r\n",
+            "        Tue, 01 Mar 2022 10:00:00 -0800 (PST)"
+        ));
+
+        assert_eq!(hop.from.as_deref(), Some("mail-sor-f41.google.com"));
+        assert_eq!(hop.from_ip.as_deref(), Some("209.85.220.41"));
+        assert_eq!(hop.by.as_deref(), Some("mx.google.com"));
+        assert_eq!(hop.with.as_deref(), Some("SMTPS"));
+        assert_eq!(hop.id.as_deref(), Some("x1sor123.2022.03.01"));
+        assert_eq!(hop.recipient.as_deref(), Some("me@example.com"));
+        assert_eq!(hop.timestamp, Some(1646157600));
+    }
+
+    #[test]
+    fn received_ipv6() {
+        let hop = ReceivedHop::parse("from [IPv6:2001:db8::1] by localhost");
+        assert_eq!(hop.from_ip.as_deref(), Some("2001:db8::1"));
+        assert_eq!(hop.by.as_deref(), Some("localhost"));
+        assert_eq!(hop.timestamp, None);
+    }
+
+    #[test]
+    fn arc() {
+        let raw = concat!(
+            "ARC-Seal: i=2; a=rsa-sha256; t=1646157600; cv=pass;\r\n",
+            "        d=google.com; s=arc-20160816;\r\n",
+            "        b=abc\r\n",
+            "ARC-Message-Signature: i=2; a=rsa-sha256; c=relaxed/relaxed; d=google.com;\r\n",
+            "        s=arc-20160816; h=from:to; bh=xyz; b=abc\r\n",
+            "ARC-Authentication-Results: i=2; mx.google.com; dkim=pass header.i=@example.com\r\n",
+            "ARC-Seal: i=1; a=rsa-sha256; cv=none; d=example.com; s=sel; b=abc\r\n",
+            "\r\n"
+        );
+        let (headers, _) = mailparse::parse_headers(raw.as_bytes()).unwrap();
+        let sets = parse_arc(&headers);
+
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].instance, 1);
+        assert_eq!(sets[0].chain_validation.as_deref(), Some("none"));
+        assert_eq!(sets[1].instance, 2);
+        assert_eq!(sets[1].chain_validation.as_deref(), Some("pass"));
+        assert_eq!(
+            sets[1]
+                .seal
+                .as_ref()
+                .unwrap()
+                .timestamp,
+            Some(1646157600)
+        );
+        assert_eq!(
+            sets[1]
+                .message_signature
+                .as_ref()
+                .unwrap()
+                .selector
+                .as_deref(),
+            Some("arc-20160816")
+        );
+        assert_eq!(
+            sets[1]
+                .authentication_results
+                .as_ref()
+                .unwrap()
+                .verdict("dkim"),
+            Some("pass")
+        );
+    }
+}
@@ -1,6 +1,6 @@
 #![feature(let_chains)]
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use email::Email;
 use imap::extensions::idle::SetReadTimeout;
 use imap::{self};
@@ -8,11 +8,41 @@ use std::io::{Read, Write};
 pub mod action;
 pub mod args;
 pub mod binary_libs;
+pub mod clustering;
+pub mod commands;
 pub mod config;
 pub mod email;
+pub mod mailbox;
+pub mod mbox;
+pub mod projection;
+pub mod schema;
+pub mod store;
+pub mod threading;
+pub mod uidplus;
+pub mod wire;
 
-// TODO: add option to open mailbox in read-only (with .examine() instead of .select())
 pub fn login(config: &config::Config) -> Result<imap::Session<impl Read + Write + SetReadTimeout>> {
+    Ok(login_to(config, "INBOX", false)?.0)
+}
+
+/// A mailbox we've selected. UIDs only mean anything together with these.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selected {
+    /// The server's name for it.
+    pub name: String,
+    /// Changes when the server renumbers the mailbox, which makes UIDs
+    /// from before then meaningless. Servers are required to send it, but
+    /// not all do.
+    pub uid_validity: Option<u32>,
+}
+
+/// Log in and select `mailbox` rather than INBOX, read-only (with EXAMINE)
+/// if asked.
+pub fn login_to(
+    config: &config::Config,
+    mailbox: &str,
+    read_only: bool,
+) -> Result<(imap::Session<impl Read + Write + SetReadTimeout>, Selected)> {
     let client = imap::ClientBuilder::new(
         &config
             .connection
@@ -36,9 +66,52 @@ pub fn login(config: &config::Config) -> Result<imap::Session<impl Read + Write
         .map_err(|e| e.0)
         .context("Login failed")?;
 
-    imap_session.select("INBOX")?;
+    let selected = if read_only {
+        examine(mailbox, &mut imap_session)?
+    } else {
+        select(mailbox, &mut imap_session)?
+    };
+
+    Ok((imap_session, selected))
+}
+
+/// Select a mailbox by its server name.
+pub fn select(
+    mailbox: &str,
+    session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+) -> Result<Selected> {
+    let status = session
+        .select(mailbox)
+        .with_context(|| format!("Couldn't select {}", mailbox))?;
+    Ok(Selected {
+        name: mailbox.to_string(),
+        uid_validity: status.uid_validity,
+    })
+}
+
+/// Select a mailbox without being able to change anything in it, not even
+/// the \Recent and \Seen flags.
+pub fn examine(
+    mailbox: &str,
+    session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+) -> Result<Selected> {
+    let status = session
+        .examine(mailbox)
+        .with_context(|| format!("Couldn't examine {}", mailbox))?;
+    Ok(Selected {
+        name: mailbox.to_string(),
+        uid_validity: status.uid_validity,
+    })
+}
 
-    Ok(imap_session)
+/// Whether the selected mailbox has a message with this UID.
+pub fn uid_exists(
+    uid: u32,
+    session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+) -> Result<bool> {
+    Ok(session
+        .uid_search(format!("UID {}", uid))?
+        .contains(&uid))
 }
 
 /// Special behavior: if uid=0, then it fetches the latest message.
@@ -56,21 +129,26 @@ pub fn fetch_email(
     };
     let query = "(UID FLAGS INTERNALDATE RFC822 ENVELOPE)";
     let messages = session.uid_fetch(uid, query)?;
-    Email::from_fetch(
+    Ok(Email::from_fetch(
         messages
             .get(0)
             .context("Empty fetches iterator -- wrong UID?")?,
-    )
+    )?)
 }
 
-/// Delete a message. Note that no error will be returned if the UID doesn't
-/// exist.
+/// Delete a message for good: flag it \Deleted and expunge it. Returns true
+/// if the server expunged it by itself as soon as the flag was set (Gmail's
+/// "Auto-Expunge" setting does this), in which case what happened to the
+/// message is up to the server; Gmail, for one, only removes it from the
+/// mailbox, and it's still in All Mail. Note that no error will be returned
+/// if the UID doesn't exist.
 pub fn delete(
     uid: u32,
     session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
-) -> Result<()> {
-    session.uid_store(&uid.to_string(), "+FLAGS (\\DELETED)")?;
-    let _deleted = session.uid_expunge(&uid.to_string())?;
+) -> Result<bool> {
+    let capabilities = session.capabilities()?;
+    let commands = commands::delete(&uid.to_string(), &capabilities)?;
+    let outcome = commands::run(uid, &commands, session)?;
 
     // now we check that a message was actually deleted. There's no error if you
     // call uid_expunge on a non-existant UID, in this context that probably
@@ -94,17 +172,100 @@ pub fn delete(
     //     return Err(anyhow!("Failed to delete UID: {}", uid));
     // }
 
-    Ok(())
+    Ok(outcome.auto_expunged)
 }
 
-// Move a message. Internally, it's a two-step copy and delete process. Note that
-// no error will be returned if you give it a non-existant UID.
+/// Move a message, returning its UID in the destination mailbox if the server
+/// told us (it will if it supports UIDPLUS). See `commands::move_message` for
+/// how. Note that no error will be returned if you give it a non-existant
+/// UID.
 pub fn move_email(
     uid: u32,
     mailbox_name: &str,
     session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+) -> Result<Option<u32>> {
+    let capabilities = session.capabilities()?;
+    let commands = commands::move_message(&uid.to_string(), mailbox_name, &capabilities)?;
+    Ok(commands::run(uid, &commands, session)?.new_uid)
+}
+
+/// Copy a message into another mailbox, leaving the original where it is.
+/// Returns the copy's UID if the server told us (it will if it supports
+/// UIDPLUS).
+pub fn copy_email(
+    uid: u32,
+    mailbox_name: &str,
+    session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+) -> Result<Option<u32>> {
+    let commands = commands::copy_message(&uid.to_string(), mailbox_name)?;
+    Ok(commands::run(uid, &commands, session)?.new_uid)
+}
+
+/// Quote a string, such as a mailbox name, for use in a command.
+pub(crate) fn quote(s: &str) -> Result<String> {
+    if s.contains(['\r', '\n']) {
+        return Err(anyhow!("Can't send a line break in a quoted string: {:?}", s));
+    }
+    Ok(format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    ))
+}
+
+/// The UID of the one message in the selected mailbox with this Message-ID.
+/// It's an error if there isn't exactly one, since acting on a guess could
+/// hit the wrong message.
+pub fn find_by_message_id(
+    message_id: &str,
+    mailbox: &Selected,
+    session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+) -> Result<u32> {
+    // HEADER matches substrings, so keep the angle brackets on to stop
+    // "1@example.com" from matching "<21@example.com>".
+    let message_id = format!(
+        "<{}>",
+        message_id
+            .trim()
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+    );
+    let mut uids: Vec<u32> = session
+        .uid_search(format!("HEADER Message-ID {}", quote(&message_id)?))?
+        .into_iter()
+        .collect();
+    uids.sort_unstable();
+
+    match uids[..] {
+        [uid] => Ok(uid),
+        [] => Err(anyhow!("No message in {} has Message-ID {}", mailbox.name, message_id)),
+        _ => Err(anyhow!(
+            "{} messages in {} have Message-ID {} (UIDs {:?}), so not acting on any of them",
+            uids.len(),
+            mailbox.name,
+            message_id,
+            uids
+        )),
+    }
+}
+
+/// Set flags or keywords on a message, e.g. `\Seen` to mark it as read.
+pub fn add_flags(
+    uid: u32,
+    flags: &[String],
+    session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+) -> Result<()> {
+    commands::run(uid, &commands::add_flags(&uid.to_string(), flags)?, session)?;
+    Ok(())
+}
+
+/// Clear flags or keywords on a message. Clearing one that isn't set isn't an
+/// error.
+pub fn remove_flags(
+    uid: u32,
+    flags: &[String],
+    session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
 ) -> Result<()> {
-    session.uid_mv(&uid.to_string(), mailbox_name)?;
-    delete(uid, session)?;
+    commands::run(uid, &commands::remove_flags(&uid.to_string(), flags)?, session)?;
     Ok(())
 }
@@ -0,0 +1,429 @@
+//! Turning the mailbox names people write in actions into the ones the server
+//! uses.
+//!
+//! In an action, "/" always separates levels of the hierarchy, whatever the
+//! server uses (often "." instead), and names are plain UTF-8. On the wire
+//! IMAP wants the server's own delimiter and modified UTF-7 (RFC 3501
+//! 5.1.3), so "Rechnungen/Größe" might need to become "Rechnungen.Gr&APYA3w-e".
+//!
+//! A name can also be a special-use role (RFC 6154) like `\Archive` or
+//! `\Trash`, which is looked up in the attributes the server sends with LIST,
+//! so rules don't need to know that Gmail calls it "[Gmail]/Trash".
+
+use anyhow::{anyhow, Context, Result};
+use imap::extensions::idle::SetReadTimeout;
+use imap::types::NameAttribute;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+
+use crate::commands;
+use crate::quote;
+
+/// The roles from RFC 6154.
+pub const SPECIAL_USES: &[&str] = &[
+    "\\All",
+    "\\Archive",
+    "\\Drafts",
+    "\\Flagged",
+    "\\Junk",
+    "\\Sent",
+    "\\Trash",
+];
+
+/// Resolves names for a session, remembering what it's learned so each name
+/// only costs a round trip the first time.
+#[derive(Debug, Default)]
+pub struct Mailboxes {
+    create_missing: bool,
+    /// None until we've asked. The server can answer with no delimiter at
+    /// all, if it doesn't do hierarchy.
+    delimiter: Option<Option<String>>,
+    /// Server names we know exist.
+    existing: HashSet<String>,
+    /// Role to server name, once we've listed the mailboxes.
+    special_use: Option<HashMap<String, String>>,
+    /// Don't create anything; just note the commands that would have.
+    dry_run: bool,
+    commands: Vec<String>,
+}
+
+impl Mailboxes {
+    /// With `create_missing`, a mailbox that doesn't exist yet is created,
+    /// along with any levels above it that don't exist either.
+    pub fn new(create_missing: bool) -> Mailboxes {
+        Mailboxes {
+            create_missing,
+            ..Default::default()
+        }
+    }
+
+    /// For trying things out: every name is checked, and missing mailboxes
+    /// are an error unless `create_missing`, but nothing is created. Instead,
+    /// `take_commands` has the CREATEs that would have been sent.
+    pub fn new_dry_run(create_missing: bool) -> Mailboxes {
+        Mailboxes {
+            create_missing,
+            dry_run: true,
+            ..Default::default()
+        }
+    }
+
+    /// The commands a dry run didn't send since this was last called.
+    pub fn take_commands(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.commands)
+    }
+
+    /// The server's name for `name`, which is either a path like
+    /// "Rechnungen/Größe" or a role like `\Archive`.
+    pub fn resolve(
+        &mut self,
+        name: &str,
+        session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+    ) -> Result<String> {
+        if name.starts_with('\\') {
+            return self.special_use(name, session);
+        }
+
+        let delimiter = self.delimiter(session)?;
+        let server_name = server_name(name, delimiter.as_deref());
+
+        if self.create_missing || self.dry_run {
+            self.ensure(name, delimiter.as_deref(), self.create_missing, session)?;
+        }
+
+        Ok(server_name)
+    }
+
+    /// The mailbox the server has for `role`, or if it hasn't marked one,
+    /// `fallback`: a path, as for `resolve`, that's created if it doesn't exist
+    /// yet, whatever `create_missing` says. That's what mail clients do for
+    /// Trash, on servers that don't do special-use.
+    pub fn resolve_or_create(
+        &mut self,
+        role: &str,
+        fallback: &str,
+        session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+    ) -> Result<String> {
+        if let Some(name) = self.find_special_use(role, session)? {
+            return Ok(name);
+        }
+        let delimiter = self.delimiter(session)?;
+        self.ensure(fallback, delimiter.as_deref(), true, session)?;
+        Ok(server_name(fallback, delimiter.as_deref()))
+    }
+
+    fn delimiter(
+        &mut self,
+        session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+    ) -> Result<Option<String>> {
+        if let Some(delimiter) = &self.delimiter {
+            return Ok(delimiter.clone());
+        }
+        // LIST with an empty pattern is the standard way to ask for the
+        // delimiter.
+        let names = session
+            .list(Some(""), Some("\"\""))
+            .context("Couldn't get the hierarchy delimiter")?;
+        let delimiter = names
+            .iter()
+            .next()
+            .and_then(|n| n.delimiter())
+            .map(str::to_string);
+        self.delimiter = Some(delimiter.clone());
+        Ok(delimiter)
+    }
+
+    /// Roles can't be created: a server that has no mailbox for one doesn't
+    /// have anywhere to put it that its other clients would recognise.
+    fn special_use(
+        &mut self,
+        role: &str,
+        session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+    ) -> Result<String> {
+        self.find_special_use(role, session)?
+            .ok_or_else(|| anyhow!("The server doesn't have a {} mailbox", role))
+    }
+
+    fn find_special_use(
+        &mut self,
+        role: &str,
+        session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+    ) -> Result<Option<String>> {
+        let role = SPECIAL_USES
+            .iter()
+            .find(|r| r.eq_ignore_ascii_case(role))
+            .ok_or_else(|| anyhow!("Unknown special-use mailbox: {}", role))?;
+
+        if self
+            .special_use
+            .is_none()
+        {
+            // Servers with SPECIAL-USE may send the attributes with a plain
+            // LIST, but only have to if they're asked for.
+            let pattern = if session
+                .capabilities()?
+                .has_str("LIST-EXTENDED")
+            {
+                "\"*\" RETURN (SPECIAL-USE)"
+            } else {
+                "\"*\""
+            };
+            let names = session
+                .list(Some(""), Some(pattern))
+                .context("Couldn't list mailboxes")?;
+            let names: Vec<(String, Vec<String>)> = names
+                .iter()
+                .map(|n| {
+                    let attributes = n
+                        .attributes()
+                        .iter()
+                        .filter_map(|a| match a {
+                            NameAttribute::Custom(a) => Some(a.to_string()),
+                            _ => None,
+                        })
+                        .collect();
+                    (n.name().to_string(), attributes)
+                })
+                .collect();
+            self.special_use = Some(roles(&names));
+        }
+
+        Ok(self
+            .special_use
+            .as_ref()
+            .and_then(|roles| roles.get(*role))
+            .cloned())
+    }
+
+    /// Check the mailbox exists. If we're creating missing ones, that goes
+    /// for every level above it too, and missing ones are created top down.
+    fn ensure(
+        &mut self,
+        name: &str,
+        delimiter: Option<&str>,
+        create: bool,
+        session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+    ) -> Result<()> {
+        let levels: Vec<&str> = match delimiter {
+            Some(_) => name
+                .split('/')
+                .collect(),
+            None => vec![name],
+        };
+        let shallowest = if create { 1 } else { levels.len() };
+        for depth in shallowest..=levels.len() {
+            let level = server_name(&levels[..depth].join("/"), delimiter);
+            if self
+                .existing
+                .contains(&level)
+            {
+                continue;
+            }
+            // LIST takes a pattern, so a `%` or `*` in the name would match
+            // other mailboxes too; only an exact match means it exists.
+            let quoted = quote(&level)?;
+            let found = session
+                .list(Some(""), Some(&quoted))?
+                .iter()
+                .any(|found| found.name() == level);
+            if !found {
+                if !create {
+                    return Err(anyhow!(
+                        "There's no mailbox {} (--create-mailboxes would create it)",
+                        name
+                    ));
+                }
+                let command = commands::create(&level)?;
+                if self.dry_run {
+                    self.commands
+                        .push(command);
+                } else {
+                    session
+                        .run(&command)
+                        .with_context(|| format!("Couldn't create mailbox {}", name))?;
+                }
+            }
+            self.existing
+                .insert(level);
+        }
+        Ok(())
+    }
+}
+
+/// Map each role to the first mailbox that has it.
+fn roles(names: &[(String, Vec<String>)]) -> HashMap<String, String> {
+    let mut roles = HashMap::new();
+    for (name, attributes) in names {
+        for attribute in attributes {
+            if let Some(role) = SPECIAL_USES
+                .iter()
+                .find(|r| r.eq_ignore_ascii_case(attribute))
+            {
+                roles
+                    .entry(role.to_string())
+                    .or_insert_with(|| name.clone());
+            }
+        }
+    }
+    roles
+}
+
+/// Swap "/" for the server's delimiter and encode each level.
+pub fn server_name(name: &str, delimiter: Option<&str>) -> String {
+    match delimiter {
+        Some(delimiter) => name
+            .split('/')
+            .map(encode_utf7)
+            .collect::<Vec<_>>()
+            .join(delimiter),
+        None => encode_utf7(name),
+    }
+}
+
+/// Printable ASCII stands for itself, apart from "&", which becomes "&-".
+/// Anything else is written as UTF-16 in a variant of base64, between "&" and
+/// "-".
+pub fn encode_utf7(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut pending: Vec<u16> = vec![];
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush_utf16(&mut pending, &mut out);
+            if c == '&' {
+                out.push_str("&-");
+            } else {
+                out.push(c);
+            }
+        } else {
+            let mut buf = [0; 2];
+            pending.extend_from_slice(c.encode_utf16(&mut buf));
+        }
+    }
+    flush_utf16(&mut pending, &mut out);
+    out
+}
+
+/// The reverse of `encode_utf7`, for showing names from the server to people.
+/// Returns None if the name isn't valid modified UTF-7.
+pub fn decode_utf7(name: &str) -> Option<String> {
+    let mut out = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        let end = rest[amp..].find('-')? + amp;
+        let encoded = &rest[amp + 1..end];
+        if encoded.is_empty() {
+            out.push('&');
+        } else {
+            let bytes = decode_base64(encoded)?;
+            if bytes.len() % 2 != 0 {
+                return None;
+            }
+            let units: Vec<u16> = bytes
+                .chunks(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            out.push_str(&String::from_utf16(&units).ok()?);
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+/// Standard base64, except with "," in place of "/".
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+fn flush_utf16(pending: &mut Vec<u16>, out: &mut String) {
+    if pending.is_empty() {
+        return;
+    }
+    let bytes: Vec<u8> = pending
+        .drain(..)
+        .flat_map(u16::to_be_bytes)
+        .collect();
+    out.push('&');
+    // No padding: the "-" marks the end.
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out.push('-');
+}
+
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in encoded.bytes() {
+        let value = ALPHABET
+            .iter()
+            .position(|&a| a == c)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modified_utf7() {
+        for (plain, encoded) in [
+            ("INBOX", "INBOX"),
+            ("Größe", "Gr&APYA3w-e"),
+            ("Tom & Jerry", "Tom &- Jerry"),
+            ("台北", "&U,BTFw-"),
+            ("~peter/mail/日本語/台北", "~peter/mail/&ZeVnLIqe-/&U,BTFw-"),
+            ("📧", "&2D3c5w-"),
+        ] {
+            assert_eq!(encode_utf7(plain), encoded);
+            assert_eq!(decode_utf7(encoded).as_deref(), Some(plain));
+        }
+        assert_eq!(decode_utf7("&unterminated"), None);
+    }
+
+    #[test]
+    fn special_use_roles() {
+        let names = vec![
+            ("INBOX".to_string(), vec![]),
+            (
+                "[Gmail]/All Mail".to_string(),
+                vec!["\\All".to_string(), "\\HasNoChildren".to_string()],
+            ),
+            (
+                "[Gmail]/Trash".to_string(),
+                vec!["\\HasNoChildren".to_string(), "\\trash".to_string()],
+            ),
+            ("Old Trash".to_string(), vec!["\\Trash".to_string()]),
+        ];
+        let roles = roles(&names);
+        assert_eq!(roles["\\All"], "[Gmail]/All Mail");
+        assert_eq!(roles["\\Trash"], "[Gmail]/Trash");
+        assert_eq!(roles.len(), 2);
+    }
+
+    #[test]
+    fn hierarchy() {
+        assert_eq!(
+            server_name("Rechnungen/Größe", Some(".")),
+            "Rechnungen.Gr&APYA3w-e"
+        );
+        assert_eq!(
+            server_name("Rechnungen/Größe", Some("/")),
+            "Rechnungen/Gr&APYA3w-e"
+        );
+        assert_eq!(server_name("a/b", None), "a/b");
+    }
+}
@@ -0,0 +1,92 @@
+//! Reading messages out of mbox files, for feeding saved mail through the
+//! pipeline without an IMAP server.
+
+/// Does this look like an mbox rather than a single message?
+pub fn is_mbox(data: &[u8]) -> bool {
+    data.starts_with(b"From ")
+}
+
+/// Split an mbox into its messages. Each message starts with a "From " line
+/// after a blank line (or at the very start), which isn't part of the
+/// message. Lines in the body that start with "From " were escaped as
+/// ">From " when the mbox was written, so we take one ">" back off (the
+/// mboxrd convention, which also does the right thing for mboxo nearly
+/// always).
+pub fn split(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = vec![];
+    let mut current: Option<Vec<u8>> = None;
+    let mut previous_blank = true;
+
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        if previous_blank && line.starts_with(b"From ") {
+            messages.extend(current.take());
+            current = Some(vec![]);
+            previous_blank = false;
+            continue;
+        }
+        previous_blank = line == b"\n" || line == b"\r\n";
+
+        let message = match current.as_mut() {
+            Some(message) => message,
+            // Junk before the first "From " line.
+            None => continue,
+        };
+        let quotes = line
+            .iter()
+            .take_while(|&&b| b == b'>')
+            .count();
+        if quotes > 0 && line[quotes..].starts_with(b"From ") {
+            message.extend_from_slice(&line[1..]);
+        } else {
+            message.extend_from_slice(line);
+        }
+    }
+    messages.extend(current);
+
+    // The blank line before each separator belongs to the format, not the
+    // message.
+    for message in &mut messages {
+        if message.ends_with(b"\r\n\r\n") {
+            message.truncate(message.len() - 2);
+        } else if message.ends_with(b"\n\n") {
+            message.truncate(message.len() - 1);
+        }
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_and_unescapes() {
+        let mbox = concat!(
+            "From bob@example.com Mon May  2 09:14:00 2022\n",
+            "Subject: one\n",
+            "\n",
+            ">From the top.\n",
+            ">>From here.\n",
+            "\n",
+            "From alice@example.com Mon May  2 10:00:00 2022\n",
+            "Subject: two\n",
+            "\n",
+            "Body two\n",
+        );
+        assert!(is_mbox(mbox.as_bytes()));
+        let messages = split(mbox.as_bytes());
+        assert_eq!(
+            messages,
+            vec![
+                b"Subject: one\n\nFrom the top.\n>From here.\n".to_vec(),
+                b"Subject: two\n\nBody two\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn from_in_body_without_blank_line() {
+        let mbox = "From a\nSubject: x\n\nline\nFrom b is not a separator\n";
+        assert_eq!(split(mbox.as_bytes()).len(), 1);
+    }
+}
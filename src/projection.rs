@@ -0,0 +1,187 @@
+//! Selecting and excluding fields from an email before it's written out.
+//!
+//! Fields are named by their path, with dots between levels, e.g. `subject`
+//! or `headers.List-Id`. Arrays are looked through, so `received.from` means
+//! the "from" of every hop. Names are matched case-insensitively, since header
+//! names are.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Projection {
+    include: Vec<Vec<String>>,
+    exclude: Vec<Vec<String>>,
+}
+
+impl Projection {
+    /// Build a projection from comma-separated lists of paths. With no
+    /// include list, everything is included.
+    pub fn parse(include: Option<&str>, exclude: Option<&str>) -> Projection {
+        Projection {
+            include: include
+                .map(parse_paths)
+                .unwrap_or_default(),
+            exclude: exclude
+                .map(parse_paths)
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Serialize `value` and apply the projection to it.
+    pub fn project<T: Serialize>(&self, value: &T) -> serde_json::Result<Value> {
+        Ok(self.apply(serde_json::to_value(value)?))
+    }
+
+    pub fn apply(&self, value: Value) -> Value {
+        let mut value = if self.include.is_empty() {
+            value
+        } else {
+            let paths: Vec<&[String]> = self
+                .include
+                .iter()
+                .map(|p| p.as_slice())
+                .collect();
+            select(&value, &paths)
+        };
+
+        for path in &self.exclude {
+            remove(&mut value, path);
+        }
+
+        value
+    }
+}
+
+fn parse_paths(list: &str) -> Vec<Vec<String>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            p.split('.')
+                .map(str::to_string)
+                .collect()
+        })
+        .collect()
+}
+
+/// Keep only what's on one of the paths.
+fn select(value: &Value, paths: &[&[String]]) -> Value {
+    // A path that ends here means "all of it".
+    if paths
+        .iter()
+        .any(|p| p.is_empty())
+    {
+        return value.clone();
+    }
+
+    match value {
+        Value::Object(map) => {
+            let mut out = Map::new();
+            for (key, child) in map {
+                let rest: Vec<&[String]> = paths
+                    .iter()
+                    .filter(|p| p[0].eq_ignore_ascii_case(key))
+                    .map(|p| &p[1..])
+                    .collect();
+                if !rest.is_empty() {
+                    out.insert(key.clone(), select(child, &rest));
+                }
+            }
+            Value::Object(out)
+        }
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| select(item, paths))
+                .collect(),
+        ),
+        // The path goes deeper than the data does; there's nothing to narrow
+        // down, so keep it.
+        _ => value.clone(),
+    }
+}
+
+fn remove(value: &mut Value, path: &[String]) {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    match value {
+        Value::Object(map) if rest.is_empty() => map.retain(|key, _| !first.eq_ignore_ascii_case(key)),
+        Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                if first.eq_ignore_ascii_case(key) {
+                    remove(child, rest);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                remove(item, path);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn email() -> Value {
+        json!({
+            "sender": ["bob@example.com"],
+            "subject": "Hi",
+            "body": "secret",
+            "uid": 3,
+            "headers": {"List-Id": ["<dev.example.com>"], "X-Spam": ["no"]},
+            "received": [{"from": "a", "by": "b"}, {"from": "c", "by": "d"}],
+        })
+    }
+
+    #[test]
+    fn include() {
+        let p = Projection::parse(Some("sender, subject,headers.list-id"), None);
+        assert_eq!(
+            p.apply(email()),
+            json!({
+                "sender": ["bob@example.com"],
+                "subject": "Hi",
+                "headers": {"List-Id": ["<dev.example.com>"]},
+            })
+        );
+    }
+
+    #[test]
+    fn exclude() {
+        let p = Projection::parse(None, Some("body,headers.X-Spam,received.by"));
+        let projected = p.apply(email());
+        assert!(projected.get("body").is_none());
+        assert_eq!(projected["headers"], json!({"List-Id": ["<dev.example.com>"]}));
+        assert_eq!(projected["received"], json!([{"from": "a"}, {"from": "c"}]));
+        assert_eq!(projected["uid"], 3);
+    }
+
+    #[test]
+    fn include_through_arrays_then_exclude() {
+        let p = Projection::parse(Some("uid,received"), Some("received.from"));
+        assert_eq!(
+            p.apply(email()),
+            json!({"uid": 3, "received": [{"by": "b"}, {"by": "d"}]})
+        );
+    }
+
+    #[test]
+    fn empty_is_identity() {
+        let p = Projection::parse(Some(""), None);
+        assert!(p.is_empty());
+        assert_eq!(p.apply(email()), email());
+    }
+}
@@ -0,0 +1,86 @@
+//! The JSON that passes between fetcher, runner, executor and your scripts is
+//! described by `email::Email` and `action::Message`. This module turns those
+//! definitions into JSON Schema, so scripts in other languages can validate
+//! against them (or generate types from them) instead of copying the shape by
+//! hand.
+
+use crate::action;
+use crate::email;
+use anyhow::{anyhow, Result};
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use serde_json::json;
+
+/// Bump this whenever a change to `Email` or `Message` could break a
+/// consumer: renaming or removing a field, or changing what it means. Adding
+/// optional fields doesn't count.
+///
+/// 2: Mailbox names in actions are UTF-8 paths with `/` between levels,
+/// rather than passed to the server as they are.
+/// 3: `Delete` moves to Trash unless the executor is set to expunge.
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// The oldest version we can still read. Raise it when a change means older
+/// input would be misread rather than just missing the new parts. A version 1
+/// `Delete` is still read, and gets the safer version 3 behaviour.
+pub const OLDEST_SUPPORTED_VERSION: u32 = 1;
+
+/// Refuse input from a version we don't know, most likely a consumer built
+/// against a newer release than this one.
+pub fn check_version(version: u32) -> Result<()> {
+    if (OLDEST_SUPPORTED_VERSION..=SCHEMA_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Unknown schema_version {}; this build reads {} to {}",
+            version,
+            OLDEST_SUPPORTED_VERSION,
+            SCHEMA_VERSION
+        ))
+    }
+}
+
+/// Used by serde for input that predates versioning.
+pub fn default_version() -> u32 {
+    1
+}
+
+pub fn email_schema() -> RootSchema {
+    schema_for!(email::Email)
+}
+
+pub fn message_schema() -> RootSchema {
+    schema_for!(action::Message)
+}
+
+/// Both schemas in one document, keyed by type.
+pub fn all_schemas() -> serde_json::Value {
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "email": email_schema(),
+        "message": message_schema(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schemas_describe_the_types() {
+        let schemas = all_schemas();
+        let email_properties = &schemas["email"]["properties"];
+        assert!(email_properties["subject"].is_object());
+        assert!(email_properties["schema_version"].is_object());
+        assert_eq!(schemas["message"]["title"], "Message");
+        assert_eq!(schemas["schema_version"], SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn versions() {
+        assert!(check_version(SCHEMA_VERSION).is_ok());
+        assert!(check_version(default_version()).is_ok());
+        assert!(check_version(0).is_err());
+        assert!(check_version(SCHEMA_VERSION + 1).is_err());
+    }
+}
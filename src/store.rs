@@ -0,0 +1,63 @@
+//! State that fetcher keeps in files between runs, like the thread store.
+//!
+//! A file is saved by writing a copy next to it and renaming that over the
+//! original, so a crash part way through leaves the old file as it was rather
+//! than half of the new one.
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Load `file`, or start from the default if it doesn't exist yet. `what` names
+/// the file in errors, like "threads".
+pub fn load<T: DeserializeOwned + Default>(file: &str, what: &str) -> Result<T> {
+    match fs::read_to_string(file) {
+        Ok(s) => serde_json::from_str(&s).with_context(|| format!("The {} file is corrupt.", what)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn save<T: Serialize>(value: &T, file: &str, what: &str) -> Result<()> {
+    let temporary = format!("{}.tmp", file);
+    fs::write(&temporary, serde_json::to_string(value)?)
+        .and_then(|()| fs::rename(&temporary, file))
+        .with_context(|| format!("Couldn't write the {} file!", what))
+}
+
+/// Seconds since the epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn save_and_load() {
+        let file = std::env::temp_dir().join(format!("store-{}.json", uuid::Uuid::new_v4()));
+        let file = file
+            .to_str()
+            .unwrap();
+
+        let missing: HashMap<String, i64> = load(file, "test").unwrap();
+        assert!(missing.is_empty());
+
+        let saved = HashMap::from([("a".to_string(), 1)]);
+        save(&saved, file, "test").unwrap();
+        let loaded: HashMap<String, i64> = load(file, "test").unwrap();
+        assert_eq!(loaded, saved);
+        assert!(!std::path::Path::new(&format!("{}.tmp", file)).exists());
+
+        fs::write(file, "{").unwrap();
+        assert!(load::<HashMap<String, i64>>(file, "test").is_err());
+        fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,553 @@
+//! Conversation threading, following Jamie Zawinski's algorithm
+//! (https://www.jwz.org/doc/threading.html). Messages only ever arrive one at
+//! a time here, so rather than rebuilding the whole tree on each run we keep
+//! the parent links around in a file and add to them as mail comes in.
+//!
+//! The thread ID is the Message-ID of the root of the tree. The root is often
+//! a message we've never seen (it's just the first entry in References), but
+//! that's fine -- it's the same for every message in the conversation.
+//!
+//! The file only remembers recent mail. A link is dropped once nothing in its
+//! thread has arrived for `MAX_AGE`, and the oldest go first if there are more
+//! than `MAX_LINKS`. That doesn't split long threads: a late reply's
+//! References lead back to the same root anyway.
+
+use crate::email::Email;
+use crate::store;
+use anyhow::Result;
+use mailparse::MailAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const THREADS_FILE: &str = "threads.json";
+
+/// How long a thread is remembered after its last message, in seconds.
+pub const MAX_AGE: i64 = 180 * 24 * 60 * 60;
+
+/// The most parent links kept.
+pub const MAX_LINKS: usize = 100_000;
+
+/// How recent a thread has to be for a reply to join it on its subject alone,
+/// in seconds.
+pub const SUBJECT_WINDOW: i64 = 30 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ThreadStore {
+    /// Message-ID to its parent. Messages that start a thread aren't in here.
+    parents: HashMap<String, Link>,
+    /// Normalized subject to the threads recently started with it, for replies
+    /// that come in without any References or In-Reply-To (JWZ step 5).
+    subjects: HashMap<String, Vec<SubjectThread>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Link {
+    parent: String,
+    /// When a message in this thread last arrived.
+    seen: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SubjectThread {
+    thread: String,
+    /// Everyone who's sent or received a message in it. A reply only joins
+    /// if it has someone in common, so an unrelated "Re: Invoice" doesn't.
+    participants: Vec<String>,
+    seen: i64,
+}
+
+impl ThreadStore {
+    /// Load the store from `file`, or start an empty one if it doesn't exist
+    /// yet.
+    pub fn load(file: &str) -> Result<ThreadStore> {
+        store::load(file, "threads")
+    }
+
+    pub fn save(&self, file: &str) -> Result<()> {
+        store::save(self, file, "threads")
+    }
+
+    /// Record the message as arriving now and return the ID of the thread it
+    /// belongs to.
+    pub fn add(&mut self, email: &Email) -> String {
+        self.add_at(email, store::now())
+    }
+
+    pub fn add_at(&mut self, email: &Email, now: i64) -> String {
+        self.expire(now);
+
+        // Link every reference to the one after it. References are oldest
+        // first, so each one is the parent of the next. We don't overwrite
+        // links we already know about, since an older message may have had
+        // better information.
+        for pair in email
+            .references
+            .windows(2)
+        {
+            let (parent, child) = (&pair[0], &pair[1]);
+            if !self
+                .parents
+                .contains_key(child)
+                && !self.would_loop(parent, child)
+            {
+                self.link(child, parent, now);
+            }
+        }
+
+        let id = match &email.message_id {
+            Some(id) => id.clone(),
+            None => return self.thread_without_id(email, now),
+        };
+
+        // The message itself is always a child of the last reference, even if
+        // we thought otherwise before.
+        match email
+            .references
+            .last()
+        {
+            Some(parent) if !self.would_loop(parent, &id) => self.link(&id, parent, now),
+            Some(_) => {}
+            None => self.group_by_subject(&id, email, now),
+        }
+
+        self.touch(&id, now);
+        self.root(&id)
+    }
+
+    /// Nothing can refer to a message without a Message-ID, so there's no
+    /// point remembering it, and an ID made up from its UID could clash with
+    /// another message's later on. It still joins the thread it replies to,
+    /// and otherwise gets a thread of its own.
+    fn thread_without_id(&mut self, email: &Email, now: i64) -> String {
+        if let Some(parent) = email
+            .references
+            .last()
+        {
+            self.touch(parent, now);
+            return self.root(parent);
+        }
+        let (normalized, is_reply) = normalize_subject(&email.subject);
+        let participants = participants(email);
+        self.subjects
+            .get(&normalized)
+            .filter(|_| is_reply)
+            .and_then(|threads| {
+                threads
+                    .iter()
+                    .find(|t| {
+                        t.participants
+                            .iter()
+                            .any(|p| participants.contains(p))
+                    })
+            })
+            .map(|t| self.root(&t.thread))
+            .unwrap_or_else(|| format!("{}@uid.invalid", email.uid))
+    }
+
+    fn link(&mut self, child: &str, parent: &str, now: i64) {
+        self.parents
+            .insert(
+                child.to_string(),
+                Link {
+                    parent: parent.to_string(),
+                    seen: now,
+                },
+            );
+    }
+
+    /// Mark the whole chain up from `id` as seen, so an active thread isn't
+    /// forgotten from the top down.
+    fn touch(&mut self, id: &str, now: i64) {
+        let mut current = id.to_string();
+        for _ in 0..=self
+            .parents
+            .len()
+        {
+            match self
+                .parents
+                .get_mut(&current)
+            {
+                Some(link) => {
+                    link.seen = now;
+                    current = link
+                        .parent
+                        .clone();
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn expire(&mut self, now: i64) {
+        self.expire_to(now, MAX_LINKS);
+    }
+
+    fn expire_to(&mut self, now: i64, max_links: usize) {
+        self.parents
+            .retain(|_, link| now - link.seen < MAX_AGE);
+        if self.parents.len() > max_links {
+            let mut oldest: Vec<(i64, String)> = self
+                .parents
+                .iter()
+                .map(|(id, link)| (link.seen, id.clone()))
+                .collect();
+            oldest.sort_unstable();
+            for (_, id) in &oldest[..oldest.len() - max_links] {
+                self.parents
+                    .remove(id);
+            }
+        }
+
+        for threads in self
+            .subjects
+            .values_mut()
+        {
+            threads.retain(|t| now - t.seen < SUBJECT_WINDOW);
+        }
+        self.subjects
+            .retain(|_, threads| !threads.is_empty());
+    }
+
+    /// Follow the parent links up to the top.
+    pub fn root(&self, id: &str) -> String {
+        let mut current = id;
+        // The loop checks in `add` should make cycles impossible, but a
+        // hand-edited file could still have one, so don't spin forever.
+        for _ in 0..=self
+            .parents
+            .len()
+        {
+            match self
+                .parents
+                .get(current)
+            {
+                Some(link) => current = &link.parent,
+                None => break,
+            }
+        }
+        current.to_string()
+    }
+
+    /// Would making `parent` the parent of `child` create a cycle?
+    fn would_loop(&self, parent: &str, child: &str) -> bool {
+        parent == child || self.is_ancestor(child, parent)
+    }
+
+    fn is_ancestor(&self, ancestor: &str, id: &str) -> bool {
+        let mut current = id;
+        for _ in 0..=self
+            .parents
+            .len()
+        {
+            match self
+                .parents
+                .get(current)
+            {
+                Some(link) if link.parent == ancestor => return true,
+                Some(link) => current = &link.parent,
+                None => return false,
+            }
+        }
+        false
+    }
+
+    /// A message with no references is either the start of a thread, or a
+    /// reply from a client that doesn't bother with the headers. In the second
+    /// case the subject is all we have to go on, so it has to be a recent
+    /// thread with someone in common.
+    fn group_by_subject(&mut self, id: &str, email: &Email, now: i64) {
+        let (normalized, is_reply) = normalize_subject(&email.subject);
+        if normalized.is_empty() {
+            return;
+        }
+        let participants = participants(email);
+
+        let threads = self
+            .subjects
+            .entry(normalized)
+            .or_default();
+        let existing = threads
+            .iter_mut()
+            .find(|t| {
+                t.participants
+                    .iter()
+                    .any(|p| participants.contains(p))
+            });
+        match existing {
+            Some(existing) if is_reply && existing.thread != id => {
+                existing.seen = now;
+                for p in participants {
+                    if !existing
+                        .participants
+                        .contains(&p)
+                    {
+                        existing
+                            .participants
+                            .push(p);
+                    }
+                }
+                let thread = existing
+                    .thread
+                    .clone();
+                if !self.would_loop(&thread, id) {
+                    self.link(id, &thread, now);
+                }
+            }
+            Some(_) => {}
+            None => threads.push(SubjectThread {
+                thread: id.to_string(),
+                participants,
+                seen: now,
+            }),
+        }
+    }
+}
+
+/// Every address in From, To and Cc, lowercased.
+fn participants(email: &Email) -> Vec<String> {
+    let mut addresses: Vec<String> = email
+        .sender
+        .iter()
+        .flatten()
+        .cloned()
+        .collect();
+    for (name, values) in &email.headers {
+        if !(name.eq_ignore_ascii_case("To") || name.eq_ignore_ascii_case("Cc")) {
+            continue;
+        }
+        for list in values
+            .iter()
+            .filter_map(|v| mailparse::addrparse(v).ok())
+        {
+            for addr in list.iter() {
+                match addr {
+                    MailAddr::Single(info) => addresses.push(info.addr.clone()),
+                    MailAddr::Group(group) => addresses.extend(
+                        group
+                            .addrs
+                            .iter()
+                            .map(|info| info.addr.clone()),
+                    ),
+                }
+            }
+        }
+    }
+    for address in &mut addresses {
+        *address = address.to_lowercase();
+    }
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+/// Pull every "<...>" out of a header value. This is more forgiving than
+/// `mailparse::msgidparse`, which gives up on the comments and junk that some
+/// clients put in In-Reply-To.
+pub fn message_ids(value: &str) -> Vec<String> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|s| s.split_once('>'))
+        .map(|(id, _)| id.trim())
+        .filter(|id| !id.is_empty())
+        .map(|id| id.to_string())
+        .collect()
+}
+
+/// Combine References and In-Reply-To into one oldest-first list, the way JWZ
+/// describes: if the In-Reply-To isn't already the last reference, it goes on
+/// the end.
+pub fn references(references: Option<&str>, in_reply_to: Option<&str>) -> Vec<String> {
+    let mut ids = references
+        .map(message_ids)
+        .unwrap_or_default();
+    if let Some(parent) = in_reply_to
+        .map(message_ids)
+        .and_then(|ids| ids.into_iter().next())
+    {
+        if ids.last() != Some(&parent) {
+            ids.retain(|id| id != &parent);
+            ids.push(parent);
+        }
+    }
+    ids
+}
+
+/// Strip "Re:", "Fwd:" and friends (and list tags like "[foo]") from the front
+/// of a subject. The bool says whether anything reply-ish was removed.
+pub fn normalize_subject(subject: &str) -> (String, bool) {
+    let mut s = subject.trim();
+    let mut is_reply = false;
+    loop {
+        if let Some(prefix) = ["re:", "fwd:", "fw:", "aw:"]
+            .iter()
+            .find(|p| {
+                s.get(..p.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(p))
+            })
+        {
+            s = s[prefix.len()..].trim_start();
+            is_reply = true;
+            continue;
+        }
+        match s.find(']') {
+            Some(end) if s.starts_with('[') => s = s[end + 1..].trim_start(),
+            _ => break,
+        }
+    }
+    (
+        s.split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase(),
+        is_reply,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn email(id: &str, references: &[&str], subject: &str) -> Email {
+        Email {
+            message_id: Some(id.to_string()),
+            references: references
+                .iter()
+                .map(|r| r.to_string())
+                .collect(),
+            subject: subject.to_string(),
+            sender: vec![Some("alice@example.com".to_string())],
+            ..Default::default()
+        }
+    }
+
+    fn email_between(id: &str, subject: &str, from: &str, to: &str) -> Email {
+        let mut email = email(id, &[], subject);
+        email.sender = vec![Some(from.to_string())];
+        email
+            .headers
+            .insert("To".to_string(), vec![to.to_string()]);
+        email
+    }
+
+    #[test]
+    fn replies_join_the_thread() {
+        let mut store = ThreadStore::default();
+        let root = store.add(&email("a@x", &[], "Lunch?"));
+        assert_eq!(root, "a@x");
+        assert_eq!(store.add(&email("b@x", &["a@x"], "Re: Lunch?")), root);
+        assert_eq!(store.add(&email("c@x", &["a@x", "b@x"], "Re: Lunch?")), root);
+        assert_ne!(store.add(&email("d@x", &[], "Something else")), root);
+    }
+
+    #[test]
+    fn reply_before_original() {
+        let mut store = ThreadStore::default();
+        // We see the reply first, so the original is just a placeholder, but
+        // the thread ID mustn't change once it shows up.
+        let thread = store.add(&email("b@x", &["a@x"], "Re: Lunch?"));
+        assert_eq!(thread, "a@x");
+        assert_eq!(store.add(&email("a@x", &[], "Lunch?")), thread);
+    }
+
+    #[test]
+    fn subject_fallback() {
+        let mut store = ThreadStore::default();
+        let thread = store.add(&email("a@x", &[], "[team] Lunch?"));
+        assert_eq!(store.add(&email("b@x", &[], "RE: Re: [team] lunch?")), thread);
+    }
+
+    #[test]
+    fn subject_fallback_needs_someone_in_common() {
+        let mut store = ThreadStore::default();
+        let thread = store.add_at(
+            &email_between("a@x", "Invoice", "billing@shop.example", "Bob <bob@example.com>"),
+            0,
+        );
+        let stranger = email_between("b@x", "Re: Invoice", "carol@example.org", "dave@example.org");
+        assert_ne!(store.add_at(&stranger, DAY), thread);
+        let reply = email_between("c@x", "Re: Invoice", "BOB@example.com", "billing@shop.example");
+        assert_eq!(store.add_at(&reply, DAY), thread);
+    }
+
+    #[test]
+    fn subject_fallback_only_for_recent_threads() {
+        let mut store = ThreadStore::default();
+        let thread = store.add_at(&email("a@x", &[], "Invoice"), 0);
+        assert_ne!(
+            store.add_at(&email("b@x", &[], "Re: Invoice"), SUBJECT_WINDOW + 1),
+            thread
+        );
+    }
+
+    #[test]
+    fn old_threads_are_forgotten() {
+        let mut store = ThreadStore::default();
+        store.add_at(&email("b@x", &["a@x"], "Re: Lunch?"), 0);
+        store.add_at(&email("d@x", &["c@x"], "Re: Dinner?"), MAX_AGE / 2);
+        // A reply keeps the whole thread alive.
+        store.add_at(&email("e@x", &["c@x", "d@x"], "Re: Dinner?"), MAX_AGE);
+
+        store.add_at(&email("z@x", &[], "Unrelated"), MAX_AGE + 1);
+        assert_eq!(store.root("b@x"), "b@x");
+        assert_eq!(store.root("d@x"), "c@x");
+        assert_eq!(store.root("e@x"), "c@x");
+    }
+
+    #[test]
+    fn capped() {
+        let mut store = ThreadStore::default();
+        for i in 0..20 {
+            store.add_at(&email(&format!("{}@x", i), &["root@x"], ""), i);
+        }
+        store.expire_to(20, 10);
+        assert_eq!(store.parents.len(), 10);
+        assert_eq!(store.root("9@x"), "9@x");
+        assert_eq!(store.root("10@x"), "root@x");
+    }
+
+    #[test]
+    fn no_loops() {
+        let mut store = ThreadStore::default();
+        store.add(&email("b@x", &["a@x"], ""));
+        // A broken client claiming the parent is a reply to its own child.
+        let thread = store.add(&email("a@x", &["b@x"], ""));
+        assert_eq!(thread, "a@x");
+        assert_eq!(store.root("b@x"), "a@x");
+    }
+
+    #[test]
+    fn messages_without_ids_arent_remembered() {
+        let mut store = ThreadStore::default();
+        let root = store.add(&email("a@x", &[], "Lunch?"));
+
+        let mut reply = email("", &["a@x"], "Re: Lunch?");
+        reply.message_id = None;
+        reply.uid = 7;
+        assert_eq!(store.add(&reply), root);
+        reply.references = vec![];
+        assert_eq!(store.add(&reply), root);
+
+        let mut lone = email("", &[], "Something else");
+        lone.message_id = None;
+        lone.uid = 7;
+        assert_eq!(store.add(&lone), "7@uid.invalid");
+        assert!(!store
+            .parents
+            .keys()
+            .any(|id| id.ends_with("@uid.invalid")));
+        assert_eq!(store.subjects.len(), 1);
+    }
+
+    #[test]
+    fn merges_in_reply_to() {
+        assert_eq!(
+            references(Some("<a@x> <b@x>"), Some("<c@x> (sent by bob)")),
+            vec!["a@x", "b@x", "c@x"]
+        );
+        assert_eq!(references(None, Some("junk <a@x>")), vec!["a@x"]);
+        assert_eq!(references(Some("<a@x> <b@x>"), Some("<b@x>")), vec!["a@x", "b@x"]);
+    }
+}
@@ -0,0 +1,107 @@
+//! The COPYUID response code from UIDPLUS (RFC 4315), which tells us the UIDs
+//! messages got in the mailbox they were copied or moved to. MOVE (RFC 6851)
+//! reuses it rather than having a code of its own, sending it in an untagged
+//! OK before the EXPUNGEs, where COPY puts it in the tagged OK at the end.
+
+/// The UID sets are limited to this many UIDs when expanded, since a buggy
+/// server could otherwise have us allocate "1:4294967295".
+const MAX_UIDS: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyUid {
+    /// UIDVALIDITY of the destination mailbox.
+    pub uid_validity: u32,
+    pub source: Vec<u32>,
+    pub destination: Vec<u32>,
+}
+
+impl CopyUid {
+    /// Find the COPYUID response code anywhere in a raw server response.
+    pub fn from_response(response: &[u8]) -> Option<CopyUid> {
+        let text = String::from_utf8_lossy(response);
+        let start = text
+            .to_ascii_uppercase()
+            .find("[COPYUID ")?;
+        let rest = &text[start + "[COPYUID ".len()..];
+        let code = &rest[..rest.find(']')?];
+
+        let mut fields = code.split_ascii_whitespace();
+        let uid_validity = fields
+            .next()?
+            .parse()
+            .ok()?;
+        let source = expand(fields.next()?)?;
+        let destination = expand(fields.next()?)?;
+        if source.len() != destination.len() {
+            return None;
+        }
+
+        Some(CopyUid {
+            uid_validity,
+            source,
+            destination,
+        })
+    }
+
+    /// The UID the message that was `uid` in the source mailbox has now.
+    pub fn destination_of(&self, uid: u32) -> Option<u32> {
+        self.source
+            .iter()
+            .position(|&u| u == uid)
+            .map(|i| self.destination[i])
+    }
+}
+
+/// "4,7:9" to [4, 7, 8, 9]. The order matters: the nth source UID became the
+/// nth destination UID. Ranges can be written either way round, and the RFC
+/// says a range means the same thing whichever way it's written.
+fn expand(set: &str) -> Option<Vec<u32>> {
+    let mut uids = vec![];
+    for part in set.split(',') {
+        let (low, high) = match part.split_once(':') {
+            Some((a, b)) => {
+                let (a, b): (u32, u32) = (a.parse().ok()?, b.parse().ok()?);
+                (a.min(b), a.max(b))
+            }
+            None => {
+                let uid = part.parse().ok()?;
+                (uid, uid)
+            }
+        };
+        if uids.len() + (high - low) as usize >= MAX_UIDS {
+            return None;
+        }
+        uids.extend(low..=high);
+    }
+    Some(uids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_response() {
+        let response = b"A003 OK [COPYUID 38505 304,319:320 3956:3958] Done\r\n";
+        let copy = CopyUid::from_response(response).unwrap();
+        assert_eq!(copy.uid_validity, 38505);
+        assert_eq!(copy.source, vec![304, 319, 320]);
+        assert_eq!(copy.destination, vec![3956, 3957, 3958]);
+        assert_eq!(copy.destination_of(319), Some(3957));
+        assert_eq!(copy.destination_of(1), None);
+    }
+
+    #[test]
+    fn move_response() {
+        let response = b"* OK [COPYUID 432432 42 11]\r\n* 3 EXPUNGE\r\nA004 OK Move completed\r\n";
+        let copy = CopyUid::from_response(response).unwrap();
+        assert_eq!(copy.destination_of(42), Some(11));
+    }
+
+    #[test]
+    fn missing_or_malformed() {
+        assert_eq!(CopyUid::from_response(b"A003 OK COPY completed\r\n"), None);
+        assert_eq!(CopyUid::from_response(b"A003 OK [COPYUID 1 1:3 5] Done\r\n"), None);
+        assert_eq!(CopyUid::from_response(b"A003 OK [COPYUID 1 1:4294967295 1:4294967295]\r\n"), None);
+    }
+}
@@ -0,0 +1,281 @@
+//! How emails and messages are framed between the pipeline stages.
+//!
+//! The default is one JSON document per line, which is easy to read and easy
+//! to write from a script. The other formats are there for when that gets
+//! slow or fragile (big bodies, odd encodings): each document is preceded by
+//! its length as a 4-byte big-endian integer, and the payload is JSON,
+//! MessagePack or CBOR.
+//!
+//! Readers can be left on `Auto`, in which case they look at the first
+//! document and stick with whatever format it was in.
+
+use anyhow::{anyhow, Context, Result};
+use clap::ArgEnum;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+/// The biggest length-prefixed document we'll read. The prefix comes from
+/// outside, and a stray byte of text read as one could ask for gigabytes.
+pub const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+#[derive(ArgEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// Detect when reading; newline-delimited JSON when writing.
+    Auto,
+    /// Newline-delimited JSON.
+    Json,
+    /// JSON with a length prefix instead of a trailing newline.
+    LengthPrefixedJson,
+    /// Length-prefixed MessagePack.
+    Msgpack,
+    /// Length-prefixed CBOR.
+    Cbor,
+}
+
+impl Format {
+    /// What to actually write when asked for `Auto`.
+    pub fn for_writing(self) -> Format {
+        match self {
+            Format::Auto => Format::Json,
+            f => f,
+        }
+    }
+}
+
+/// Encode a single document, without any framing.
+pub fn encode<T: Serialize>(value: &T, format: Format) -> Result<Vec<u8>> {
+    Ok(match format.for_writing() {
+        Format::Json | Format::LengthPrefixedJson => serde_json::to_vec(value)?,
+        // Structs have to be written as maps, not arrays; otherwise the
+        // optional fields that get skipped when empty shift everything after
+        // them.
+        Format::Msgpack => rmp_serde::to_vec_named(value)?,
+        Format::Cbor => serde_cbor::to_vec(value)?,
+        Format::Auto => unreachable!(),
+    })
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8], format: Format) -> Result<T> {
+    Ok(match format {
+        Format::Json | Format::LengthPrefixedJson => serde_json::from_slice(bytes)?,
+        Format::Msgpack => rmp_serde::from_slice(bytes)?,
+        Format::Cbor => serde_cbor::from_slice(bytes)?,
+        Format::Auto => decode(bytes, detect_payload(bytes)?)?,
+    })
+}
+
+/// Write one framed document and flush, so the next stage sees it right away.
+pub fn write<T: Serialize>(writer: &mut impl Write, value: &T, format: Format) -> Result<()> {
+    let format = format.for_writing();
+    let payload = encode(value, format)?;
+    if format == Format::Json {
+        writer.write_all(&payload)?;
+        writer.write_all(b"\n")?;
+    } else {
+        let len = u32::try_from(payload.len()).context("Document too large to frame")?;
+        writer.write_all(&len.to_be_bytes())?;
+        writer.write_all(&payload)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Shorthand for writing to stdout, which is where every stage sends its
+/// output.
+pub fn write_stdout<T: Serialize>(value: &T, format: Format) -> Result<()> {
+    write(&mut std::io::stdout().lock(), value, format)
+}
+
+pub struct Reader<R: BufRead> {
+    inner: R,
+    format: Format,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(inner: R, format: Format) -> Reader<R> {
+        Reader { inner, format }
+    }
+
+    /// The format being read. Still `Auto` until the first document has been
+    /// read, if that's what we started with.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Read the next document. Returns None at the end of the stream.
+    pub fn read<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        match self.read_raw()? {
+            Some(bytes) => decode(&bytes, self.format).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the bytes of the next document, working out the format first if
+    /// we don't know it yet.
+    pub fn read_raw(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.format == Format::Auto {
+            let first = match self
+                .inner
+                .fill_buf()?
+                .first()
+            {
+                Some(&b) => b,
+                None => return Ok(None),
+            };
+
+            // A length prefix starts with a zero byte for anything under
+            // 16MB, which JSON text never does.
+            if first == b'{' || first.is_ascii_whitespace() {
+                self.format = Format::Json;
+            } else {
+                let payload = match self.read_frame()? {
+                    Some(payload) => payload,
+                    None => return Ok(None),
+                };
+                self.format = detect_payload(&payload)?;
+                return Ok(Some(payload));
+            }
+        }
+
+        if self.format == Format::Json {
+            // Skip blank lines rather than treating them as the end.
+            loop {
+                let mut line = String::new();
+                if self
+                    .inner
+                    .read_line(&mut line)?
+                    == 0
+                {
+                    return Ok(None);
+                }
+                if !line
+                    .trim()
+                    .is_empty()
+                {
+                    return Ok(Some(line.into_bytes()));
+                }
+            }
+        }
+
+        self.read_frame()
+    }
+
+    fn read_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut len = [0; 4];
+        match self
+            .inner
+            .read_exact(&mut len)
+        {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME {
+            return Err(anyhow!(
+                "Document length {} is over the limit of {} bytes; is the input in the format \
+                 expected?",
+                len,
+                MAX_FRAME
+            ));
+        }
+        let mut payload = vec![0; len];
+        self.inner
+            .read_exact(&mut payload)
+            .context("Stream ended in the middle of a document")?;
+        Ok(Some(payload))
+    }
+}
+
+/// Every document we send is a map at the top level, and each format spells
+/// "map" differently in its first byte.
+fn detect_payload(payload: &[u8]) -> Result<Format> {
+    match payload.first() {
+        Some(b'{') => Ok(Format::LengthPrefixedJson),
+        // fixmap, map 16, map 32
+        Some(0x80..=0x8f | 0xde | 0xdf) => Ok(Format::Msgpack),
+        // major type 5, including indefinite length
+        Some(0xa0..=0xbf) => Ok(Format::Cbor),
+        Some(b) => Err(anyhow!("Couldn't detect input format (first byte {:#04x})", b)),
+        None => Err(anyhow!("Couldn't detect input format of an empty document")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{Action, Message};
+    use crate::schema::SCHEMA_VERSION;
+
+    fn message(uid: u32) -> Message {
+        Message {
+            schema_version: SCHEMA_VERSION,
+            uid,
+            message_id: None,
+            mailbox: None,
+            uid_validity: None,
+            actions: vec![Action::Move("Spam".to_string())],
+            stop: Some(true),
+        }
+    }
+
+    #[test]
+    fn round_trip_every_format() -> Result<()> {
+        for format in [
+            Format::Json,
+            Format::LengthPrefixedJson,
+            Format::Msgpack,
+            Format::Cbor,
+        ] {
+            let mut buf = vec![];
+            write(&mut buf, &message(1), format)?;
+            write(&mut buf, &message(2), format)?;
+
+            // Both the explicit format and detection should work.
+            for read_as in [format, Format::Auto] {
+                let mut reader = Reader::new(buf.as_slice(), read_as);
+                assert_eq!(reader.read::<Message>()?, Some(message(1)));
+                assert_eq!(reader.format(), format);
+                assert_eq!(reader.read::<Message>()?, Some(message(2)));
+                assert_eq!(reader.read::<Message>()?, None);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn json_lines_skip_blanks() -> Result<()> {
+        let input = format!("\n{}\n\n", message(3));
+        let mut reader = Reader::new(input.as_bytes(), Format::Auto);
+        assert_eq!(reader.read::<Message>()?, Some(message(3)));
+        assert_eq!(reader.read::<Message>()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn garbage_length_prefix() {
+        // Plain text that doesn't look like JSON gets read as a length prefix;
+        // "Hell" would be over a gigabyte.
+        let mut reader = Reader::new("Hello there\n".as_bytes(), Format::Auto);
+        assert!(reader
+            .read::<Message>()
+            .is_err());
+        let mut reader = Reader::new(&[0xff, 0xff, 0xff, 0xff, b'{'][..], Format::Msgpack);
+        assert!(reader
+            .read::<Message>()
+            .is_err());
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut buf = vec![];
+        write(&mut buf, &message(1), Format::Msgpack).unwrap();
+        buf.truncate(buf.len() - 1);
+        let mut reader = Reader::new(buf.as_slice(), Format::Msgpack);
+        assert!(reader
+            .read::<Message>()
+            .is_err());
+    }
+}
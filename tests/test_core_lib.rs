@@ -73,3 +73,79 @@ fn test_move() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_flags() -> Result<()> {
+    let to = random_email();
+    send_email_to(&to)?;
+    let mut session = get_session(to.as_str().into())?;
+
+    let flags = vec!["\\Seen".to_string(), "Invoice".to_string()];
+    mail_client::add_flags(1, &flags, &mut session)?;
+
+    let fetches = session.uid_fetch("1", "FLAGS")?;
+    let set = fetches
+        .get(0)
+        .unwrap()
+        .flags();
+    assert!(set.contains(&imap::types::Flag::Seen));
+    assert!(set.contains(&imap::types::Flag::Custom("Invoice".into())));
+
+    mail_client::remove_flags(1, &["\\Seen".to_string()], &mut session)?;
+
+    let fetches = session.uid_fetch("1", "FLAGS")?;
+    let set = fetches
+        .get(0)
+        .unwrap()
+        .flags();
+    assert!(!set.contains(&imap::types::Flag::Seen));
+    assert!(set.contains(&imap::types::Flag::Custom("Invoice".into())));
+
+    Ok(())
+}
+
+#[test]
+fn test_move_returns_new_uid() -> Result<()> {
+    let to = random_email();
+    let second_mailbox = "movetest";
+
+    let mut session = get_session(to.as_str().into())?;
+    session.create(second_mailbox)?;
+    send_email_to(&to)?;
+    send_email_to(&to)?;
+
+    // Move the second one, so its new UID (1) differs from its old one (2).
+    let new_uid = mail_client::move_email(2, second_mailbox, &mut session)?.unwrap();
+
+    // The first message is untouched.
+    assert!(mail_client::fetch_email(1, &mut session).is_ok());
+
+    session.select(second_mailbox)?;
+    assert_eq!(1, new_uid);
+    assert!(mail_client::fetch_email(new_uid, &mut session).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_copy() -> Result<()> {
+    let to = random_email();
+    let second_mailbox = "Accounting";
+
+    let mut session = get_session(to.as_str().into())?;
+    session.create(second_mailbox)?;
+    send_email_to(&to)?;
+
+    let new_uid = mail_client::copy_email(1, second_mailbox, &mut session)?;
+
+    // Still in the inbox...
+    let mailbox = session.select("INBOX")?;
+    assert_eq!(1, mailbox.exists);
+
+    // ...and now in the other mailbox too.
+    let mailbox = session.select(second_mailbox)?;
+    assert_eq!(1, mailbox.exists);
+    assert_eq!(Some(1), new_uid);
+
+    Ok(())
+}
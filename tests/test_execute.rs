@@ -1,12 +1,18 @@
 use anyhow::Result;
 use assert_cmd::Command;
+use imap::extensions::idle::SetReadTimeout;
 use mail_client::action;
 pub mod utils;
+use std::io::{Read, Write};
 use std::thread::sleep;
 use std::time::Duration;
 use utils::*;
 
 fn run_act_on_mail(email: &str, input: &str) -> Result<Vec<String>> {
+    run_act_on_mail_with(email, input, &[])
+}
+
+fn run_act_on_mail_with(email: &str, input: &str, extra_args: &[&str]) -> Result<Vec<String>> {
     // Run the process and wait for output
     let cmd = Command::cargo_bin("executor");
     let output = cmd
@@ -19,20 +25,28 @@ fn run_act_on_mail(email: &str, input: &str) -> Result<Vec<String>> {
             "--password",
             format!("{}", &email).as_str(),
         ])
+        .args(extra_args)
         .write_stdin(input.to_string() + "\n")
         .output()?;
 
     parse_output(output)
 }
 
-#[test]
-fn test_delete() -> Result<()> {
-    let delete_second_email = action::Message {
-        uid: 2,
-        actions: vec![action::Action::Delete],
+fn message(uid: u32, actions: Vec<action::Action>) -> action::Message {
+    action::Message {
+        schema_version: mail_client::schema::SCHEMA_VERSION,
+        uid,
+        message_id: None,
+        mailbox: None,
+        uid_validity: None,
+        actions,
         stop: None,
     }
-    .to_string();
+}
+
+#[test]
+fn test_delete() -> Result<()> {
+    let delete_second_email = message(2, vec![action::Action::Delete]).to_string();
 
     let to_email = random_email();
     utils::send_email_to(&to_email)?;
@@ -52,12 +66,7 @@ fn test_delete() -> Result<()> {
 
 #[test]
 fn test_move() -> Result<()> {
-    let move_email = action::Message {
-        uid: 1,
-        actions: vec![action::Action::Move("SPAM".to_owned())],
-        stop: None,
-    }
-    .to_string();
+    let move_email = message(1, vec![action::Action::Move("SPAM".to_owned())]).to_string();
 
     let to_email = random_email();
 
@@ -83,3 +92,286 @@ fn test_move() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_move_creates_mailbox() -> Result<()> {
+    let move_email =
+        message(1, vec![action::Action::Move("Rechnungen/Größe".to_owned())]).to_string();
+
+    let to_email = random_email();
+    send_email_to(&to_email)?;
+
+    // Without the flag, a missing mailbox is still an error.
+    assert!(run_act_on_mail(&to_email, &move_email).is_err());
+    let mut session = get_session(Some(&to_email))?;
+    assert!(mail_client::fetch_email(1, &mut session).is_ok());
+
+    run_act_on_mail_with(&to_email, &move_email, &["--create-mailboxes"])?;
+    assert!(mail_client::fetch_email(1, &mut session).is_err());
+
+    let name = mail_client::mailbox::Mailboxes::new(false)
+        .resolve("Rechnungen/Größe", &mut session)?;
+    session.select(&name)?;
+    assert!(mail_client::fetch_email(1, &mut session).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_missing_special_use() -> Result<()> {
+    // Greenmail doesn't mark any mailboxes with special-use attributes, so
+    // there's nothing to archive to.
+    let archive = message(1, vec![action::Action::Archive]).to_string();
+
+    let to_email = random_email();
+    send_email_to(&to_email)?;
+
+    assert!(run_act_on_mail(&to_email, &archive).is_err());
+    let mut session = get_session(Some(&to_email))?;
+    assert!(mail_client::fetch_email(1, &mut session).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_delete_modes() -> Result<()> {
+    let delete = message(1, vec![action::Action::Delete]).to_string();
+    let delete_third = message(3, vec![action::Action::Delete]).to_string();
+    let expunge = message(2, vec![action::Action::Expunge]).to_string();
+
+    let to_email = random_email();
+    send_email_to(&to_email)?;
+    send_email_to(&to_email)?;
+    send_email_to(&to_email)?;
+
+    // Greenmail doesn't mark a Trash mailbox, so the message goes to the one
+    // in the config, which is created for it.
+    run_act_on_mail(&to_email, &delete)?;
+    let mut session = get_session(Some(&to_email))?;
+    assert!(mail_client::fetch_email(1, &mut session).is_err());
+    session.select("Trash")?;
+    assert!(mail_client::fetch_email(1, &mut session).is_ok());
+
+    // Expunge doesn't care about the mode.
+    run_act_on_mail_with(&to_email, &expunge, &["--delete-mode", "trash"])?;
+    // And the expunge mode makes Delete the same.
+    run_act_on_mail_with(&to_email, &delete_third, &["--delete-mode", "expunge"])?;
+    session.select("INBOX")?;
+    assert!(mail_client::fetch_email(2, &mut session).is_err());
+    assert!(mail_client::fetch_email(3, &mut session).is_err());
+    // Only the first one went to Trash.
+    session.select("Trash")?;
+    assert_eq!(session.uid_search("ALL")?.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_add_deleted_refused() -> Result<()> {
+    let flag_deleted =
+        message(1, vec![action::Action::AddFlags(vec!["\\deleted".to_string()])]).to_string();
+
+    let to_email = random_email();
+    send_email_to(&to_email)?;
+
+    // That would get around the delete mode, so it has to be Delete.
+    assert!(run_act_on_mail(&to_email, &flag_deleted).is_err());
+    let mut session = get_session(Some(&to_email))?;
+    let fetches = session.uid_fetch("1", "FLAGS")?;
+    assert!(!fetches
+        .iter()
+        .any(|f| f.flags().contains(&imap::types::Flag::Deleted)));
+
+    Ok(())
+}
+
+fn is_flagged(
+    mailbox: &str,
+    uid: u32,
+    session: &mut imap::Session<impl Read + Write + SetReadTimeout>,
+) -> Result<bool> {
+    mail_client::select(mailbox, session)?;
+    Ok(session
+        .uid_fetch(uid.to_string(), "FLAGS")?
+        .iter()
+        .any(|f| f.flags().contains(&imap::types::Flag::Flagged)))
+}
+
+#[test]
+fn test_mailbox_and_uid_validity() -> Result<()> {
+    let to_email = random_email();
+    send_email_to(&to_email)?;
+
+    let mut session = get_session(Some(&to_email))?;
+    session.create("Elsewhere")?;
+    mail_client::copy_email(1, "Elsewhere", &mut session)?;
+    let elsewhere = mail_client::select("Elsewhere", &mut session)?;
+    let uid_validity = elsewhere.uid_validity.unwrap_or(0);
+
+    let flag = |uid_validity| {
+        action::Message {
+            mailbox: Some("Elsewhere".to_string()),
+            uid_validity: Some(uid_validity),
+            ..message(1, vec![action::Action::AddFlags(vec!["\\Flagged".to_string()])])
+        }
+        .to_string()
+    };
+
+    // A stale UIDVALIDITY means the UID can't be trusted.
+    assert!(run_act_on_mail(&to_email, &flag(uid_validity.wrapping_add(1))).is_err());
+    assert!(!is_flagged("Elsewhere", 1, &mut session)?);
+
+    run_act_on_mail(&to_email, &flag(uid_validity))?;
+    assert!(is_flagged("Elsewhere", 1, &mut session)?);
+    // The original in INBOX wasn't touched.
+    assert!(!is_flagged("INBOX", 1, &mut session)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_bad_message_is_skipped() -> Result<()> {
+    let flag = |uid, uid_validity| {
+        action::Message {
+            uid_validity,
+            ..message(uid, vec![action::Action::AddFlags(vec!["\\Flagged".to_string()])])
+        }
+        .to_string()
+    };
+
+    let to_email = random_email();
+    send_email_to(&to_email)?;
+    send_email_to(&to_email)?;
+    send_email_to(&to_email)?;
+
+    let mut session = get_session(Some(&to_email))?;
+    let uid_validity = mail_client::select("INBOX", &mut session)?
+        .uid_validity
+        .unwrap_or(0);
+    let input = [
+        flag(1, Some(uid_validity.wrapping_add(1))),
+        flag(2, None),
+        flag(0, None),
+        flag(3, Some(uid_validity)),
+    ]
+    .join("\n");
+
+    // The stale one and the one with nothing to go on are skipped, and the
+    // run fails at the end because of them, but the others still happen.
+    assert!(run_act_on_mail_with(&to_email, &input, &["--forever", "true"]).is_err());
+    assert!(!is_flagged("INBOX", 1, &mut session)?);
+    assert!(is_flagged("INBOX", 2, &mut session)?);
+    assert!(is_flagged("INBOX", 3, &mut session)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_message_id() -> Result<()> {
+    let flag = |message_id: &str| {
+        action::Message {
+            message_id: Some(message_id.to_string()),
+            ..message(0, vec![action::Action::AddFlags(vec!["\\Flagged".to_string()])])
+        }
+        .to_string()
+    };
+
+    let to_email = random_email();
+    send_email_to(&to_email)?;
+    send_email_to(&to_email)?;
+
+    let mut session = get_session(Some(&to_email))?;
+    let message_id = mail_client::fetch_email(2, &mut session)?
+        .message_id
+        .expect("Test messages should have a Message-ID");
+
+    run_act_on_mail(&to_email, &flag(&message_id))?;
+    assert!(is_flagged("INBOX", 2, &mut session)?);
+    assert!(!is_flagged("INBOX", 1, &mut session)?);
+
+    assert!(run_act_on_mail(&to_email, &flag("<nothing-has-this@example.com>")).is_err());
+
+    // With two copies of the message, it's ambiguous which was meant.
+    mail_client::copy_email(1, "INBOX", &mut session)?;
+    let message_id = mail_client::fetch_email(1, &mut session)?
+        .message_id
+        .unwrap();
+    assert!(run_act_on_mail(&to_email, &flag(&message_id)).is_err());
+    assert!(!is_flagged("INBOX", 1, &mut session)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_actions_follow_the_message() -> Result<()> {
+    let to_email = random_email();
+    send_email_to(&to_email)?;
+    send_email_to(&to_email)?;
+
+    let mut session = get_session(Some(&to_email))?;
+    session.create("SPAM")?;
+    // Give SPAM a message of its own, so the moved one isn't UID 2 there.
+    mail_client::copy_email(1, "SPAM", &mut session)?;
+
+    let actions = vec![
+        action::Action::Move("SPAM".to_owned()),
+        action::Action::AddFlags(vec!["\\Flagged".to_string()]),
+    ];
+    run_act_on_mail(&to_email, &message(2, actions).to_string())?;
+
+    // The flag went on the message in its new home, and nothing else.
+    assert!(!is_flagged("SPAM", 1, &mut session)?);
+    assert!(is_flagged("SPAM", 2, &mut session)?);
+    assert!(!is_flagged("INBOX", 1, &mut session)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_dry_run() -> Result<()> {
+    let file_away = |uid| {
+        let actions = vec![
+            action::Action::AddFlags(vec!["\\Seen".to_string()]),
+            action::Action::Move("Rechnungen".to_owned()),
+            action::Action::Delete,
+        ];
+        message(uid, actions).to_string()
+    };
+
+    let to_email = random_email();
+    send_email_to(&to_email)?;
+
+    let commands = run_act_on_mail_with(
+        &to_email,
+        &file_away(1),
+        &["--dry-run", "--create-mailboxes"],
+    )?;
+    assert_eq!(commands[0], "UID STORE 1 +FLAGS.SILENT (\\Seen)");
+    assert_eq!(commands[1], "CREATE \"Rechnungen\"");
+    // MOVE, or COPY and the rest if the server doesn't have it.
+    assert!(commands[2].ends_with(" 1 \"Rechnungen\""));
+    let select = commands
+        .iter()
+        .position(|c| c == "SELECT \"Rechnungen\"")
+        .expect("Should have switched to the new mailbox");
+    // Delete moves it on to Trash, which doesn't exist yet either.
+    assert_eq!(commands[select + 1], "CREATE \"Trash\"");
+    assert!(commands[select + 2].ends_with(" <new UID> \"Trash\""));
+
+    // Nothing was actually done.
+    let mut session = get_session(Some(&to_email))?;
+    assert!(session
+        .list(Some(""), Some("Rechnungen"))?
+        .is_empty());
+    let fetches = session.uid_fetch("1", "FLAGS")?;
+    assert!(!fetches
+        .iter()
+        .any(|f| f.flags().contains(&imap::types::Flag::Seen)));
+
+    // A dry run complains about things a real run would quietly ignore.
+    let dry_run = ["--dry-run", "--create-mailboxes"];
+    assert!(run_act_on_mail_with(&to_email, &file_away(42), &dry_run).is_err());
+    assert!(run_act_on_mail_with(&to_email, &file_away(1), &dry_run[..1]).is_err());
+
+    Ok(())
+}
@@ -114,6 +114,8 @@ fn test_idle() -> Result<()> {
 
     let email = Email::from_json(&stdout)?;
     assert_eq!(email.subject, subject);
+    assert_eq!(email.mailbox.as_deref(), Some("INBOX"));
+    assert!(email.uid_validity.is_some());
 
     // Attempt to gracefully kill the child. Important for getting accurate code
     // coverage; LLVM can't record coverage if the program crashes.
@@ -54,6 +54,7 @@ pub fn get_config(to: Option<&str>) -> config::Config {
             port: 3993,
         },
         imap_options: None,
+        executor: None,
         scripts: None,
     }
 }